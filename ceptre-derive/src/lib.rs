@@ -0,0 +1,153 @@
+//! `#[derive(FromPhrase)]` for the `ceptre` crate, usually used through its
+//! `derive` feature.
+//!
+//! The derived pattern is the struct's name in kebab case followed by a
+//! variable for each field, named after the field in upper case, so
+//! `struct LevelInstruments { instrument: i32, sequence_id: i32 }` matches
+//! `level-instruments INSTRUMENT SEQUENCE_ID`. The fields of a tuple struct
+//! are `V0`, `V1` and so on. `#[ceptre(name = "...")]` on the struct replaces
+//! the leading atom. Each field's type must implement `ceptre::FromTerm`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+use std::iter;
+
+#[proc_macro_derive(FromPhrase, attributes(ceptre))]
+pub fn derive_from_phrase(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match from_phrase(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn from_phrase(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match input.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "FromPhrase can only be derived for structs",
+            ))
+        }
+    };
+
+    let name = match phrase_name(input)? {
+        Some(name) => name,
+        None => kebab_case(&input.ident.to_string()),
+    };
+
+    let vars = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match field.ident {
+            Some(ref ident) => ident.to_string().trim_start_matches("r#").to_uppercase(),
+            None => format!("V{}", i),
+        })
+        .collect::<Vec<_>>();
+
+    for (field, var) in fields.iter().zip(vars.iter()) {
+        if !var.starts_with(|c: char| c.is_ascii_uppercase()) {
+            return Err(syn::Error::new_spanned(
+                field,
+                "field names must start with a letter to be used as variables",
+            ));
+        }
+    }
+
+    let pattern = iter::once(name)
+        .chain(vars.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let values = vars.iter().map(|var| {
+        quote! {
+            bindings.value(#var, string_cache)?
+        }
+    });
+
+    let ident = &input.ident;
+    let body = match *fields {
+        Fields::Named(_) => {
+            let idents = fields.iter().map(|f| f.ident.as_ref().expect("ident"));
+            quote! { #ident { #(#idents: #values),* } }
+        }
+        Fields::Unnamed(_) => quote! { #ident(#(#values),*) },
+        Fields::Unit => quote! { #ident },
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::ceptre::FromPhrase for #ident #ty_generics #where_clause {
+            fn pattern() -> ::std::string::String {
+                #pattern.to_string()
+            }
+
+            #[allow(unused_variables)]
+            fn from_bindings(
+                bindings: &::ceptre::Bindings,
+                string_cache: &::ceptre::StringCache,
+            ) -> ::std::result::Result<Self, ::ceptre::FromPhraseError> {
+                ::std::result::Result::Ok(#body)
+            }
+        }
+    })
+}
+
+// The value of `#[ceptre(name = "...")]`, if given.
+fn phrase_name(input: &DeriveInput) -> syn::Result<Option<String>> {
+    let mut name = None;
+
+    for attr in input.attrs.iter() {
+        if !attr.path.is_ident("ceptre") {
+            continue;
+        }
+
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "expected `#[ceptre(name = \"...\")]`",
+                ))
+            }
+        };
+
+        for nested in list.nested.iter() {
+            match *nested {
+                NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "name" => match nv.lit {
+                    Lit::Str(ref s) => name = Some(s.value()),
+                    _ => return Err(syn::Error::new_spanned(&nv.lit, "expected a string")),
+                },
+                _ => return Err(syn::Error::new_spanned(nested, "unknown attribute")),
+            }
+        }
+    }
+
+    Ok(name)
+}
+
+// `LevelInstruments` becomes `level-instruments`.
+fn kebab_case(ident: &str) -> String {
+    let mut name = String::new();
+
+    for (i, c) in ident.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('-');
+        }
+
+        name.extend(c.to_lowercase());
+    }
+
+    name
+}
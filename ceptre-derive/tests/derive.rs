@@ -0,0 +1,64 @@
+extern crate ceptre;
+
+use ceptre::{Atom, Context, FromPhrase};
+
+#[derive(Debug, FromPhrase, PartialEq)]
+struct Note {
+    instrument: i32,
+    pos: i32,
+    desc: String,
+}
+
+#[derive(Debug, FromPhrase, PartialEq)]
+#[ceptre(name = "note-tmp")]
+struct TempNote(i32, i32, Atom);
+
+#[derive(Debug, FromPhrase, PartialEq)]
+struct LevelInstruments {
+    instrument: i64,
+    sequence: Vec<i64>,
+}
+
+#[derive(Debug, FromPhrase, PartialEq)]
+struct Started;
+
+#[test]
+fn derived_patterns() {
+    assert_eq!(Note::pattern(), "note INSTRUMENT POS DESC");
+    assert_eq!(TempNote::pattern(), "note-tmp V0 V1 V2");
+    assert_eq!(
+        LevelInstruments::pattern(),
+        "level-instruments INSTRUMENT SEQUENCE"
+    );
+    assert_eq!(Started::pattern(), "started");
+}
+
+#[test]
+fn derived_query() {
+    let mut context = Context::from_text(
+        "note 0 3 first . note-tmp 1 4 last\n\
+         level-instruments 2 (0 1 1 0)\n\
+         started",
+    )
+    .unwrap();
+
+    let notes = context.query::<Note>().unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        notes,
+        vec![Note {
+            instrument: 0,
+            pos: 3,
+            desc: "first".to_string(),
+        }]
+    );
+
+    let last = context.to_atom("last");
+    let notes = context.query::<TempNote>().unwrap().collect::<Vec<_>>();
+    assert_eq!(notes, vec![TempNote(1, 4, last)]);
+
+    let level = context.query::<LevelInstruments>().unwrap().next().unwrap();
+    assert_eq!(level.instrument, 2);
+    assert_eq!(level.sequence, vec![0, 1, 1, 0]);
+
+    assert_eq!(context.query::<Started>().unwrap().count(), 1);
+}
@@ -0,0 +1,97 @@
+extern crate ceptre;
+
+use ceptre::{Context, Matcher};
+use std::time::Instant;
+
+// A synthetic program where a single stage drains `size` facts,
+// surrounded by unrelated facts that the matcher has to skip over.
+fn collect_program(size: usize) -> String {
+    let mut text = String::from(
+        "#collect:\n\
+         \x20 item I . !collected I = collected I\n\
+         \x20 () =\n\n",
+    );
+
+    for i in 0..size {
+        text.push_str(&format!("item {}\n", i));
+        text.push_str(&format!("noise-{} {}\n", i % 50, i));
+    }
+
+    text
+}
+
+// A synthetic program where a stage joins two sets of `size` facts, only half
+// of which have a partner.
+fn join_program(size: usize) -> String {
+    let mut text = String::from(
+        "#join:\n\
+         \x20 left K . right K = paired K\n\
+         \x20 () =\n\n",
+    );
+
+    for i in 0..size {
+        text.push_str(&format!("left {}\n", i));
+        text.push_str(&format!("right {}\n", i + size / 2));
+    }
+
+    text
+}
+
+fn main() {
+    for &matcher in [Matcher::Indexed, Matcher::Incremental].iter() {
+        println!("{:?}", matcher);
+
+        for &size in [250, 500, 1000, 2000].iter() {
+            let mut context = Context::from_text_with_seed(&collect_program(size), 0).unwrap();
+            run("collect", &mut context, matcher, None);
+
+            let pattern = context.pattern("collected I").unwrap();
+            assert_eq!(context.find(&pattern).count(), size);
+            let pattern = context.pattern("item I").unwrap();
+            assert!(context.find(&pattern).next().is_none());
+        }
+
+        // the same with cycle detection, which hashes the state on each firing
+        for &size in [250, 500, 1000, 2000].iter() {
+            let mut context = Context::from_text_with_seed(&collect_program(size), 0).unwrap();
+            run("collect", &mut context, matcher, Some(size * 2));
+
+            let pattern = context.pattern("collected I").unwrap();
+            assert_eq!(context.find(&pattern).count(), size);
+        }
+
+        for &size in [250, 500, 1000, 2000].iter() {
+            let mut context = Context::from_text_with_seed(&join_program(size), 0).unwrap();
+            run("join", &mut context, matcher, None);
+
+            let pattern = context.pattern("paired K").unwrap();
+            assert_eq!(context.find(&pattern).count(), size - size / 2);
+        }
+    }
+}
+
+// Runs the rules with `update`, or with `update_with_limit` if there's a limit.
+fn run(name: &str, context: &mut Context, matcher: Matcher, max_steps: Option<usize>) {
+    context.set_matcher(matcher);
+    context.append_state(&format!("#{}", name)).unwrap();
+
+    let facts = context.state.len() - 1;
+
+    let start = Instant::now();
+    match max_steps {
+        Some(max_steps) => {
+            let outcome = ceptre::update_with_limit(context, max_steps, |_: &ceptre::Phrase| None);
+            assert_eq!(outcome, ceptre::UpdateOutcome::Quiescent);
+        }
+        None => ceptre::update(context, |_: &ceptre::Phrase| None),
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:>8} {:>5} facts{}: {:>9.2} ms",
+        name,
+        facts,
+        if max_steps.is_some() { " (limit)" } else { "" },
+        elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0
+    );
+}
@@ -0,0 +1,313 @@
+extern crate ceptre;
+extern crate rand;
+
+use ceptre::{Context, Firing, Phrase, StringCache, UpdateOutcome};
+
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+
+const USAGE: &str = "usage: ceptre-repl [--seed N] [FILE...]";
+
+const HELP: &str = "\
+load FILE...      load a program, reseeded with the current seed
+reload            reload the rules of the loaded files, keeping the state
+assert PHRASE     add a phrase to the state, such as `#input-place 3`
+retract PATTERN   remove the phrases matching a pattern
+update [N]        fire rules until none match, or at most N rules
+step [N]          fire one rule, or N rules, printing each firing
+find PATTERN      print the bindings of each match of a pattern
+state             print the state
+rules             print the rules
+enabled           print the rules that match the state
+seed [N]          print the seed, or reseed with N
+trace on|off      print every rule fired by `update`
+help              print this message
+quit              exit";
+
+// the most rules that `update` fires, so that a rule loop can't hang the repl.
+const MAX_UPDATE_STEPS: usize = 100_000;
+
+struct Repl {
+    context: Context,
+    files: Vec<String>,
+    seed: u64,
+    // the firings traced since the last command
+    firings: Rc<RefCell<Vec<String>>>,
+    is_tracing: bool,
+}
+
+fn main() {
+    let mut seed = rand::random::<u64>();
+    let mut files = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(s) => seed = s,
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let mut repl = Repl::new(seed);
+
+    if !files.is_empty() {
+        if let Err(e) = repl.load(files) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    println!("seed {}, `help` for commands", repl.seed);
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("flush");
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+
+        let line = line.trim();
+        let (command, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => (),
+            "quit" | "exit" => break,
+            _ => {
+                if let Err(e) = repl.run(command, rest) {
+                    println!("error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Repl {
+    fn new(seed: u64) -> Repl {
+        let mut repl = Repl {
+            context: Context::from_text_with_seed("", seed).expect("empty program"),
+            files: vec![],
+            seed,
+            firings: Rc::new(RefCell::new(vec![])),
+            is_tracing: false,
+        };
+
+        repl.set_trace();
+        repl
+    }
+
+    fn run(&mut self, command: &str, rest: &str) -> Result<(), String> {
+        match command {
+            "load" => self.load(rest.split_whitespace().map(String::from).collect())?,
+            "reload" => {
+                if self.files.is_empty() {
+                    return Err("no files loaded".to_string());
+                }
+
+                let files = self.files.clone();
+                self.context
+                    .reload_rules(&files)
+                    .map_err(|e| e.to_string())?;
+                println!("{} rules", self.context.rules().len());
+            }
+            "assert" => self.context.append_state(rest).map_err(|e| e.to_string())?,
+            "retract" => {
+                let pattern = self.context.pattern(rest).map_err(|e| e.to_string())?;
+                let removed = self.context.retract_matching(&pattern);
+                println!("removed {}", removed);
+            }
+            "update" => {
+                let limit = optional_number(rest)?.unwrap_or(MAX_UPDATE_STEPS);
+                let outcome =
+                    ceptre::update_with_limit(&mut self.context, limit, |_: &Phrase| None);
+                let firings = self.firings.replace(vec![]);
+
+                if self.is_tracing {
+                    for firing in firings.iter() {
+                        println!("{}", firing);
+                    }
+                }
+
+                match outcome {
+                    UpdateOutcome::Quiescent => {
+                        println!("quiescent after {} firings", firings.len())
+                    }
+                    UpdateOutcome::StepLimitReached => {
+                        println!("stopped after {} firings", firings.len())
+                    }
+                    UpdateOutcome::CycleDetected { rule_ids } => {
+                        println!(
+                            "rules {:?} fire in a loop, after {} firings",
+                            rule_ids,
+                            firings.len()
+                        )
+                    }
+                }
+            }
+            "step" => {
+                for _ in 0..optional_number(rest)?.unwrap_or(1) {
+                    if ceptre::step(&mut self.context, |_: &Phrase| None).is_none() {
+                        println!("quiescent");
+                        break;
+                    }
+
+                    for firing in self.firings.replace(vec![]).iter() {
+                        println!("{}", firing);
+                    }
+                }
+            }
+            "find" | "query" => {
+                let pattern = self.context.pattern(rest).map_err(|e| e.to_string())?;
+                let mut count = 0;
+
+                for bindings in self.context.find(&pattern) {
+                    let values = bindings
+                        .iter()
+                        .map(|(var, value)| {
+                            format!(
+                                "{} = {}",
+                                var,
+                                ceptre::term_to_string(value, &self.context.string_cache)
+                            )
+                        })
+                        .collect::<Vec<_>>();
+
+                    println!(
+                        "{}",
+                        if values.is_empty() {
+                            "yes".to_string()
+                        } else {
+                            values.join(", ")
+                        }
+                    );
+                    count += 1;
+                }
+
+                if count == 0 {
+                    println!("no matches");
+                }
+            }
+            "state" => {
+                let mut state = self
+                    .context
+                    .state
+                    .iter()
+                    .map(|p| ceptre::phrase_to_string(p, &self.context.string_cache))
+                    .collect::<Vec<_>>();
+                state.sort();
+
+                for phrase in state.iter() {
+                    println!("{}", phrase);
+                }
+            }
+            "rules" => {
+                let mut rules = self.context.rules().iter().collect::<Vec<_>>();
+                rules.sort_by_key(|r| r.id);
+
+                for rule in rules {
+                    println!(
+                        "{}",
+                        ceptre::rule_to_string(rule, &self.context.string_cache)
+                    );
+                }
+            }
+            "enabled" => {
+                let rules = self
+                    .context
+                    .enabled_rules(|_: &Phrase| None)
+                    .iter()
+                    .map(|r| ceptre::rule_to_string(r, &self.context.string_cache))
+                    .collect::<Vec<_>>();
+
+                if rules.is_empty() {
+                    println!("no rules match");
+                }
+
+                for rule in rules.iter() {
+                    println!("{}", rule);
+                }
+            }
+            "seed" => match optional_number(rest)? {
+                Some(seed) => {
+                    self.seed = seed;
+                    self.context.reseed(self.seed);
+                }
+                None => println!("{}", self.seed),
+            },
+            "trace" => match rest {
+                "on" => self.is_tracing = true,
+                "off" => self.is_tracing = false,
+                _ => return Err("expected `on` or `off`".to_string()),
+            },
+            "help" => println!("{}", HELP),
+            _ => {
+                return Err(format!(
+                    "unknown command `{}`, `help` for commands",
+                    command
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self, files: Vec<String>) -> Result<(), String> {
+        if files.is_empty() {
+            return Err("expected a file".to_string());
+        }
+
+        let mut context = Context::from_files(&files).map_err(|e| e.to_string())?;
+        context.reseed(self.seed);
+
+        self.context = context;
+        self.files = files;
+        self.set_trace();
+
+        println!("{} rules", self.context.rules().len());
+
+        Ok(())
+    }
+
+    // Collects the firings of the context, to print after each command.
+    fn set_trace(&mut self) {
+        let firings = self.firings.clone();
+        self.context
+            .set_trace(move |firing: &Firing, string_cache: &StringCache| {
+                firings
+                    .borrow_mut()
+                    .push(ceptre::firing_to_string(firing, string_cache));
+            });
+    }
+}
+
+// The number after a command, if any.
+fn optional_number<T: FromStr>(text: &str) -> Result<Option<T>, String> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    text.parse()
+        .map(Some)
+        .map_err(|_| format!("expected a number, not `{}`", text))
+}
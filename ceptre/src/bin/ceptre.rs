@@ -0,0 +1,62 @@
+extern crate ceptre;
+
+use std::env;
+use std::process;
+
+const USAGE: &str = "usage: ceptre lint [--external NAME]... FILE...";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    match args.first().map(String::as_str) {
+        Some("lint") => process::exit(lint(&args[1..])),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+// Prints the lints of the program in `args`, returning the exit code.
+fn lint(args: &[String]) -> i32 {
+    let mut external = vec![];
+    let mut paths = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--external" => match args.next() {
+                Some(name) => external.push(name.as_str()),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            _ => paths.push(arg.as_str()),
+        }
+    }
+
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    let context = match ceptre::Context::from_files(&paths) {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let lints = context.lint(&external);
+    for lint in lints.iter() {
+        println!("{}", lint);
+    }
+
+    if lints.is_empty() {
+        0
+    } else {
+        1
+    }
+}
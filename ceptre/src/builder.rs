@@ -0,0 +1,111 @@
+use super::number::Number;
+use super::parser::{self, ParseError, TermAst};
+use super::{first_token, is_quiescence, phrase_to_string, Phrase, StringCache, Term};
+
+/// Builds a `Phrase` from atoms, numbers and nested lists without writing it
+/// as text, for adding to the state with `Context::append_phrase`.
+///
+/// The result is the same as parsing the equivalent text, so
+/// `PhraseBuilder::new().atom("place").list(PhraseBuilder::new().atom("x").int(1))`
+/// builds `place (x 1)`. An empty list is `()`.
+#[derive(Clone, Debug, Default)]
+pub struct PhraseBuilder {
+    terms: Vec<TermAst>,
+}
+
+impl PhraseBuilder {
+    pub fn new() -> PhraseBuilder {
+        PhraseBuilder { terms: vec![] }
+    }
+
+    /// Adds an atom, written as it would be in a program, such as `note` or
+    /// `#input-place`. `build` fails if the atom is a variable or negated,
+    /// since rules can't match such a phrase in the state.
+    pub fn atom(mut self, text: &str) -> PhraseBuilder {
+        self.terms.push(TermAst::Atom(text.to_string()));
+        self
+    }
+
+    pub fn int(mut self, v: i64) -> PhraseBuilder {
+        self.terms.push(TermAst::Number(Number::Int(v)));
+        self
+    }
+
+    /// Adds a float. Unlike in a program, it may be infinite or NaN.
+    pub fn float(mut self, v: f64) -> PhraseBuilder {
+        self.terms.push(TermAst::Number(Number::Float(v)));
+        self
+    }
+
+    /// Adds the terms of `list` as a nested list.
+    pub fn list(mut self, list: PhraseBuilder) -> PhraseBuilder {
+        self.terms.push(list.into_term());
+        self
+    }
+
+    /// Fails if the phrase is empty or the quiescence marker `()`, if an atom
+    /// is empty, a variable or negated, or if the phrase starts with a
+    /// predicate such as `+` or `^collide`. An error is reported at line 1,
+    /// column 1.
+    pub fn build(&self, string_cache: &mut StringCache) -> Result<Phrase, ParseError> {
+        if self.terms.is_empty() {
+            return Err(ParseError::unparsed(String::new(), "empty phrase"));
+        }
+
+        self.terms.iter().try_for_each(check_ast)?;
+
+        let phrase = parser::terms_to_phrase(&self.terms, string_cache);
+
+        if is_quiescence(&phrase, string_cache) {
+            let text = phrase_to_string(&phrase, string_cache);
+            return Err(ParseError::unparsed(text, parser::QUIESCENCE_IN_STATE));
+        }
+
+        if let Some(token) = first_token(&phrase) {
+            if token.backwards_pred.is_some() || token.is_side {
+                let text = token.as_str(string_cache).into_owned();
+                return Err(ParseError::unparsed(text, "phrase starts with a predicate"));
+            }
+        }
+
+        phrase
+            .iter()
+            .try_for_each(|t| check_term(t, string_cache))?;
+
+        Ok(phrase)
+    }
+
+    fn into_term(self) -> TermAst {
+        TermAst::List(self.terms)
+    }
+}
+
+fn check_ast(term: &TermAst) -> Result<(), ParseError> {
+    match *term {
+        TermAst::Atom(ref s) if s.is_empty() => {
+            Err(ParseError::unparsed(String::new(), "empty atom"))
+        }
+        TermAst::List(ref terms) => terms.iter().try_for_each(check_ast),
+        _ => Ok(()),
+    }
+}
+
+fn check_term(term: &Term, string_cache: &StringCache) -> Result<(), ParseError> {
+    match *term {
+        Term::Atom(ref token) if token.is_var || token.is_negated => {
+            let negation = if token.is_negated { "!" } else { "" };
+            let text = format!("{}{}", negation, token.as_str(string_cache));
+
+            Err(ParseError::unparsed(
+                text,
+                "expected an atom without variables or negation",
+            ))
+        }
+        Term::Atom(_) => Ok(()),
+        Term::List(ref terms) => terms.iter().try_for_each(|t| check_term(t, string_cache)),
+        Term::Cons(ref terms, ref tail) => {
+            terms.iter().try_for_each(|t| check_term(t, string_cache))?;
+            check_term(tail, string_cache)
+        }
+    }
+}
@@ -0,0 +1,2397 @@
+//! A small forward-chaining rule engine, loosely based on Ceptre.
+//!
+//! A `Context` is built from program text with `Context::from_text`, facts are
+//! added with `Context::append_state` and rules are run to quiescence with
+//! `update`. Side predicates (`^name`) are resolved by the `SideInput` closure
+//! passed to `update`.
+//!
+//! Program text is a sequence of statements, one per line. A statement is
+//! either initial state (`a 1 . b 2`) or a rule (`a X . b Y = c X Y`). A
+//! statement continues onto the next line if that line starts with `.` or `=`,
+//! if the current line ends with `.`, or while brackets are open. A line of the
+//! form `#stage ARGS:` attaches the stage to each following statement until the
+//! next blank line. `//` starts a comment that runs to the end of the line and
+//! `/* */` delimits a comment that may span several lines. A line containing
+//! only comments does not end a `#stage:` block.
+//!
+//! A term is an atom, a number or a list of terms in brackets. Backwards
+//! predicates such as `+ A B C` compute and check values, and `type` lines
+//! declare the arguments of a predicate. `Context::from_text` describes them.
+//!
+//! Host code reads the state with `Context::find` and `Context::query`, and
+//! changes it with `append_state`, `append_phrase` and `retract_matching`.
+//! `step` and `update_with_limit` fire rules with more control than `update`,
+//! and `Context::save` keeps a running context to carry on from later.
+
+extern crate rand;
+
+#[cfg(feature = "derive")]
+extern crate ceptre_derive;
+
+mod builder;
+mod lint;
+mod number;
+mod parser;
+mod query;
+mod rete;
+mod rng;
+mod save;
+mod state;
+mod types;
+
+pub use builder::PhraseBuilder;
+#[cfg(feature = "derive")]
+pub use ceptre_derive::FromPhrase;
+pub use lint::{Lint, LintKind};
+pub use parser::{ParseError, Span};
+pub use query::{FromPhrase, FromPhraseError, FromTerm, QueryError};
+pub use save::{LoadError, SaveFormat};
+pub use state::State;
+pub use types::{ArgType, TypeDecl};
+
+use rand::Rng;
+
+use number::Number;
+use parser::Parsed;
+use rng::XorShiftRng;
+use types::Types;
+
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::vec::{self, Vec};
+
+/// A symbol interned in a `StringCache`, or a number. Numbers are stored
+/// directly, so arithmetic doesn't add to the cache.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Atom {
+    kind: AtomKind,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum AtomKind {
+    Symbol(usize),
+    Number(Number),
+}
+
+impl Atom {
+    pub fn int(v: i64) -> Atom {
+        Atom::number(Number::Int(v))
+    }
+
+    pub fn float(v: f64) -> Atom {
+        Atom::number(Number::Float(v))
+    }
+
+    fn number(number: Number) -> Atom {
+        Atom {
+            kind: AtomKind::Number(number),
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.as_number().is_some()
+    }
+
+    /// The value of an integer atom.
+    pub fn as_int(&self) -> Option<i64> {
+        match self.kind {
+            AtomKind::Number(Number::Int(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value of an integer or float atom.
+    pub fn as_float(&self) -> Option<f64> {
+        self.as_number().map(|n| n.to_f64())
+    }
+
+    fn as_number(&self) -> Option<Number> {
+        match self.kind {
+            AtomKind::Number(n) => Some(n),
+            AtomKind::Symbol(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum BackwardsPred {
+    Plus,
+    Minus,
+    Times,
+    Divide,
+    IntDivide,
+    Min,
+    Max,
+    Abs,
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+    ModNeg,
+    Length,
+    Append,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub string: Atom,
+    backwards_pred: Option<BackwardsPred>,
+    is_var: bool,
+    is_negated: bool,
+    is_side: bool,
+    is_stage: bool,
+}
+
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.string == other.string && self.is_side == other.is_side
+    }
+}
+impl Eq for Token {}
+
+impl Token {
+    fn new(string: &str, string_cache: &mut StringCache) -> Token {
+        let mut string = string;
+
+        let mut is_negated = false;
+        let mut is_side = false;
+        let mut is_stage = false;
+        match string.chars().next() {
+            Some('!') if string != "!=" => {
+                is_negated = true;
+                string = &string[1..];
+            }
+            Some('^') => {
+                is_side = true;
+            }
+            Some('#') => {
+                is_stage = true;
+            }
+            _ => {}
+        }
+
+        // the parser never gives an empty string, but treat one as a symbol
+        let mut chars = string.chars();
+        let is_var = chars.next().is_some_and(|c| c.is_ascii_uppercase())
+            && chars.all(|c| c.is_numeric() || !c.is_ascii_lowercase());
+
+        let backwards_pred = match string {
+            "+" => Some(BackwardsPred::Plus),
+            "-" => Some(BackwardsPred::Minus),
+            "*" => Some(BackwardsPred::Times),
+            "/" => Some(BackwardsPred::Divide),
+            "div" => Some(BackwardsPred::IntDivide),
+            "min" => Some(BackwardsPred::Min),
+            "max" => Some(BackwardsPred::Max),
+            "abs" => Some(BackwardsPred::Abs),
+            "==" => Some(BackwardsPred::Eq),
+            "!=" => Some(BackwardsPred::Neq),
+            "<" => Some(BackwardsPred::Lt),
+            ">" => Some(BackwardsPred::Gt),
+            "<=" => Some(BackwardsPred::Lte),
+            ">=" => Some(BackwardsPred::Gte),
+            "%%" => Some(BackwardsPred::ModNeg),
+            "length" => Some(BackwardsPred::Length),
+            "append" => Some(BackwardsPred::Append),
+
+            _ => None,
+        };
+
+        let atom = string_cache.to_atom(string);
+
+        Token {
+            string: atom,
+            backwards_pred,
+            is_var,
+            is_negated,
+            is_side,
+            is_stage,
+        }
+    }
+
+    fn from_atom(atom: Atom) -> Token {
+        Token {
+            string: atom,
+            backwards_pred: None,
+            is_var: false,
+            is_negated: false,
+            is_side: false,
+            is_stage: false,
+        }
+    }
+
+    pub fn as_str<'a>(&self, string_cache: &'a StringCache) -> Cow<'a, str> {
+        string_cache.from_atom(self.string)
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        self.string.as_int()
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        self.string.as_float()
+    }
+}
+
+/// A term of a phrase: an atom, which may be a number or a variable, or a
+/// list of terms.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Term {
+    Atom(Token),
+    List(Vec<Term>),
+    /// `(A B | T)` in a rule: a list starting with `A B` and continuing with
+    /// the elements of the list `T`.
+    Cons(Vec<Term>, Box<Term>),
+}
+
+impl Term {
+    fn number(number: Number) -> Term {
+        Term::Atom(Token::from_atom(Atom::number(number)))
+    }
+
+    /// The atom of a term that isn't a list.
+    pub fn as_atom(&self) -> Option<Atom> {
+        match *self {
+            Term::Atom(ref token) => Some(token.string),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        self.as_atom().and_then(|atom| atom.as_int())
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        self.as_atom().and_then(|atom| atom.as_float())
+    }
+
+    /// The elements of a list.
+    pub fn as_list(&self) -> Option<&[Term]> {
+        match *self {
+            Term::List(ref terms) => Some(terms),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<Number> {
+        self.as_atom().and_then(|atom| atom.as_number())
+    }
+
+    fn is_var(&self) -> bool {
+        match *self {
+            Term::Atom(ref token) => token.is_var,
+            _ => false,
+        }
+    }
+}
+
+/// The terms of a fact or of a rule's input or output, such as `note 0 3 (x o)`.
+pub type Phrase = Vec<Term>;
+type Match = (Atom, Term);
+
+// The atom at the start of a phrase, which decides whether the phrase is a
+// predicate, or None if the phrase starts with a list.
+fn first_token(phrase: &Phrase) -> Option<&Token> {
+    phrase.first().and_then(|term| match *term {
+        Term::Atom(ref token) => Some(token),
+        _ => None,
+    })
+}
+
+// https://stackoverflow.com/questions/44246722/is-there-any-way-to-create-an-alias-of-a-specific-fnmut
+pub trait SideInput: FnMut(&Phrase) -> Option<Phrase> {}
+impl<F> SideInput for F where F: FnMut(&Phrase) -> Option<Phrase> {}
+
+pub trait Trace: FnMut(&Firing, &StringCache) {}
+impl<F> Trace for F where F: FnMut(&Firing, &StringCache) {}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub id: i32,
+    /// Where the rule was written, or the default span if it wasn't parsed.
+    pub span: Span,
+    /// Set with `@priority N`, and 0 by default. Higher priorities are tried
+    /// first with `RuleOrder::Priority`.
+    pub priority: i32,
+    /// Set with `@weight N`, and 1 by default. Of the rules that can fire,
+    /// each fires with a probability proportional to its weight, so
+    /// `@weight 3 go = a` fires three times as often as `go = b`.
+    pub weight: u32,
+    pub inputs: Vec<Phrase>,
+    pub outputs: Vec<Phrase>,
+    /// Where each input and output was written. A stage label's span is
+    /// the label, and a `$` phrase has the same span as input and output.
+    pub input_spans: Vec<Span>,
+    pub output_spans: Vec<Span>,
+}
+
+impl Rule {
+    fn new_with_id(id: i32, inputs: Vec<Phrase>, outputs: Vec<Phrase>) -> Rule {
+        Rule {
+            id,
+            span: Span::default(),
+            priority: 0,
+            weight: 1,
+            input_spans: vec![Span::default(); inputs.len()],
+            output_spans: vec![Span::default(); outputs.len()],
+            inputs,
+            outputs,
+        }
+    }
+}
+
+/// How a `Context` finds the states matching a rule's inputs. Both matchers
+/// fire the same rules for the same seed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Matcher {
+    /// Searches the indexed state for each rule on every firing.
+    Indexed,
+    /// Keeps a network of partial matches per rule, updated as phrases are
+    /// added and removed. Faster for rules that join large sets of facts.
+    Incremental,
+}
+
+/// The order in which `update` tries rules on each firing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleOrder {
+    /// Rules are tried in a random order, weighted by their `@weight`.
+    Random,
+    /// Rules are tried in the order they appear in the source.
+    Source,
+    /// Rules with a higher `@priority` are tried first, and rules with the
+    /// same priority in a random order weighted by their `@weight`.
+    Priority,
+}
+
+pub struct Context {
+    rules: Vec<Rule>,
+    types: Types,
+    pub state: State,
+    pub string_cache: StringCache,
+    quiescence: bool,
+    rng: XorShiftRng,
+    matcher: Matcher,
+    rete: Option<rete::Rete>,
+    rule_order: RuleOrder,
+    trace: Option<Box<dyn Trace>>,
+    // the pattern of each type passed to `query`, parsed on first use
+    query_patterns: HashMap<TypeId, Pattern>,
+}
+
+pub struct StringCache {
+    atom_to_string: Vec<String>,
+    string_to_atom: HashMap<String, Atom>,
+}
+
+impl Default for StringCache {
+    fn default() -> StringCache {
+        StringCache::new()
+    }
+}
+
+impl StringCache {
+    pub fn new() -> StringCache {
+        StringCache {
+            atom_to_string: vec![],
+            string_to_atom: HashMap::new(),
+        }
+    }
+
+    pub fn to_atom(&mut self, text: &str) -> Atom {
+        if let Some(atom) = self.to_existing_atom(text) {
+            return atom;
+        }
+
+        let idx = self.atom_to_string.len();
+        let atom = Atom {
+            kind: AtomKind::Symbol(idx),
+        };
+
+        self.atom_to_string.push(text.to_string());
+        self.string_to_atom.insert(text.to_string(), atom);
+
+        atom
+    }
+
+    /// Numbers always exist, since they aren't stored in the cache.
+    pub fn to_existing_atom(&self, text: &str) -> Option<Atom> {
+        if let Some(number) = Number::parse(text) {
+            return Some(Atom::number(number));
+        }
+
+        self.string_to_atom.get(text).cloned()
+    }
+
+    pub fn from_atom<'a>(&'a self, atom: Atom) -> Cow<'a, str> {
+        match atom.kind {
+            AtomKind::Symbol(idx) => Cow::Borrowed(&self.atom_to_string[idx]),
+            AtomKind::Number(number) => Cow::Owned(number.to_string()),
+        }
+    }
+}
+
+impl Context {
+    /// Parses a program.
+    ///
+    /// Atoms starting with a digit, or `-` and a digit, are numbers, stored as
+    /// integers (`16`, `-1`) or floats (`0.5`, `16.0`). Arithmetic keeps
+    /// integer results as integers.
+    ///
+    /// Backwards predicates compute or check values. `+ A B C`, `- A B C`,
+    /// `* A B C` and `/ A B C` (where C = A op B) solve for whichever argument
+    /// is unbound, or check the equation if all are bound. `div A B C`
+    /// (division rounded down), `%% A B C`, `min A B C`, `max A B C` and
+    /// `abs A B` only solve for their last argument. `%% A B C` is the
+    /// remainder rounded down, so C has the sign of B and `%% -15 16 C` gives
+    /// C = 1. `== A B` binds either side to the other, and `!= A B`, `<`, `>`,
+    /// `<=` and `>=` check bound arguments. A predicate with no solution, such
+    /// as division by zero, fails to match.
+    ///
+    /// A list such as `(x (o (x)))` matches element by element, so `(x)`
+    /// doesn't match `x`, and `()` is the empty list. `(H | T)` matches a list
+    /// whose first element is H and whose remaining elements are the list T.
+    /// `length L N` gives the number of elements in L, and `append A B C`
+    /// (where C is A followed by B) solves for whichever list is unbound. A
+    /// phrase of a single variable, such as `RETURN` in `() = RETURN`, matches
+    /// or produces a whole phrase.
+    ///
+    /// A line such as `type note instrument:int pos:int desc:atom` declares a
+    /// predicate's arguments, as described by `TypeDecl`. `@priority N` and
+    /// `@weight N` before a rule set its `priority` and `weight`.
+    pub fn from_text(text: &str) -> Result<Context, ParseError> {
+        Context::from_text_with_seed(text, rand::random::<u64>())
+    }
+
+    /// Like `from_text`, but rules fire in a reproducible order for a given seed.
+    pub fn from_text_with_seed(text: &str, seed: u64) -> Result<Context, ParseError> {
+        Context::parse(text, None, seed)
+    }
+
+    /// Like `from_text`, but spans and errors name the text `file`.
+    pub fn from_named_text(file: &str, text: &str) -> Result<Context, ParseError> {
+        Context::parse(text, Some(file), rand::random::<u64>())
+    }
+
+    /// Reads the files at `paths` in order, along with the files they include
+    /// with `#include "path"`, and merges their initial state and rules into
+    /// one program. An included path is relative to the including file, and
+    /// replaces the `#include` line. A file is only read the first time it's
+    /// included, and a file that includes itself, directly or through other
+    /// files, is an error.
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<Context, ParseError> {
+        Context::from_files_with_reader(paths, |path: &str| fs::read_to_string(path))
+    }
+
+    /// Like `from_files`, but reads each file with `read`, which is given the
+    /// file's path, such as `levels/intro.ceptre` for `#include
+    /// "intro.ceptre"` in `levels/main.ceptre`. Useful for programs embedded
+    /// in the binary.
+    pub fn from_files_with_reader<P, F>(paths: &[P], mut read: F) -> Result<Context, ParseError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&str) -> io::Result<String>,
+    {
+        let mut string_cache = StringCache::new();
+        let program = parser::parse_files(paths, &mut read, &mut string_cache)?;
+
+        Ok(Context::new(program, string_cache, rand::random::<u64>()))
+    }
+
+    fn parse(text: &str, file: Option<&str>, seed: u64) -> Result<Context, ParseError> {
+        let mut string_cache = StringCache::new();
+        let program = parser::parse(text, file, &mut string_cache)?;
+
+        Ok(Context::new(program, string_cache, seed))
+    }
+
+    fn new(program: Parsed, string_cache: StringCache, seed: u64) -> Context {
+        let mut state = State::new();
+        for p in program.state.into_iter() {
+            state.push(p);
+        }
+
+        let rng = rng_from_seed(seed);
+
+        Context {
+            state,
+            rules: program.rules,
+            types: program.types,
+            string_cache,
+            quiescence: false,
+            rng,
+            matcher: Matcher::Indexed,
+            rete: None,
+            rule_order: RuleOrder::Priority,
+            trace: None,
+            query_patterns: HashMap::new(),
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = rng_from_seed(seed);
+    }
+
+    pub fn matcher(&self) -> Matcher {
+        self.matcher
+    }
+
+    pub fn set_matcher(&mut self, matcher: Matcher) {
+        self.matcher = matcher;
+
+        match matcher {
+            Matcher::Indexed => {
+                self.state.record_changes(false);
+                self.rete = None;
+            }
+            Matcher::Incremental => {
+                self.state.record_changes(true);
+                self.rete = Some(rete::Rete::new(&self.rules, &self.state));
+            }
+        }
+    }
+
+    pub fn rule_order(&self) -> RuleOrder {
+        self.rule_order
+    }
+
+    pub fn set_rule_order(&mut self, rule_order: RuleOrder) {
+        self.rule_order = rule_order;
+    }
+
+    /// Calls `trace` with each rule fired from now on.
+    pub fn set_trace<T>(&mut self, trace: T)
+    where
+        T: Trace + 'static,
+    {
+        self.trace = Some(Box::new(trace));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The rules that match the state, in source order. `step` fires one of
+    /// them, chosen by the rule order and priorities. If no rule matches, or
+    /// the context is quiescent, these are the rules that match with `()` in
+    /// the state. `side_input` is called for side predicates in the rules'
+    /// inputs, but not for those in their outputs, since no rule fires.
+    pub fn enabled_rules<F>(&self, mut side_input: F) -> Vec<&Rule>
+    where
+        F: SideInput,
+    {
+        let mut ids = vec![];
+
+        if !self.quiescence {
+            ids = matching_rule_ids(&self.rules, &self.state, &mut side_input);
+        }
+
+        // no rule can match `()` if the program never mentions it
+        if let (true, Some(qui)) = (ids.is_empty(), self.to_existing_atom("qui")) {
+            let mut state = State::new();
+            for p in self.state.iter() {
+                state.push(p.clone());
+            }
+            state.push(vec![Term::Atom(Token::from_atom(qui))]);
+
+            ids = matching_rule_ids(&self.rules, &state, &mut side_input);
+        }
+
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|r| ids.contains(&r.id))
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|r| r.id);
+        rules
+    }
+
+    /// The `type` declaration of a predicate, such as `note`.
+    pub fn declared_type(&self, predicate: &str) -> Option<&TypeDecl> {
+        self.to_existing_atom(predicate)
+            .and_then(|atom| self.types.get(&atom))
+    }
+
+    /// Replaces the rules with those of the files at `paths`, read as by
+    /// `from_files`, keeping the current state. The initial state in the
+    /// files is ignored. If the files can't be read or parsed, the rules are
+    /// left as they were.
+    pub fn reload_rules<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<(), ParseError> {
+        self.reload_rules_with_reader(paths, |path: &str| fs::read_to_string(path))
+    }
+
+    /// Like `reload_rules`, but reads each file with `read`, as
+    /// `from_files_with_reader` does.
+    pub fn reload_rules_with_reader<P, F>(
+        &mut self,
+        paths: &[P],
+        mut read: F,
+    ) -> Result<(), ParseError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&str) -> io::Result<String>,
+    {
+        let program = parser::parse_files(paths, &mut read, &mut self.string_cache)?;
+        self.rules = program.rules;
+        self.types = program.types;
+
+        // the new types may reject patterns that were checked against the old
+        self.query_patterns.clear();
+
+        // rebuild the partial matches of the new rules
+        let matcher = self.matcher;
+        self.set_matcher(matcher);
+
+        Ok(())
+    }
+
+    /// Checks the rules for likely mistakes: output variables that no input
+    /// binds, stages that are never produced, predicates that are produced
+    /// but never consumed or the reverse, and predicates used with different
+    /// numbers of arguments. The current state counts as produced, so lint a
+    /// context before running it. `external` names the predicates that host
+    /// code appends or reads, such as `#set-beat` or `note`.
+    pub fn lint(&self, external: &[&str]) -> Vec<Lint> {
+        lint::lint(&self.state, &self.rules, external, &self.string_cache)
+    }
+
+    pub fn to_atom(&mut self, text: &str) -> Atom {
+        self.string_cache.to_atom(text)
+    }
+
+    pub fn to_existing_atom(&self, text: &str) -> Option<Atom> {
+        self.string_cache.to_existing_atom(text)
+    }
+
+    pub fn append_state(&mut self, text: &str) -> Result<(), ParseError> {
+        let phrase = parser::parse_phrase(text, &self.types, &mut self.string_cache)?;
+        self.state.push(phrase);
+
+        Ok(())
+    }
+
+    /// Like `append_state`, for a phrase made with a `PhraseBuilder`. An error
+    /// is reported at line 1, column 1.
+    pub fn append_phrase(&mut self, phrase: Phrase) -> Result<(), ParseError> {
+        check_state_phrase(&phrase, &self.types, &self.string_cache)?;
+        self.state.push(phrase);
+
+        Ok(())
+    }
+
+    /// Removes one phrase equal to `phrase` from the state, returning whether
+    /// there was one.
+    pub fn retract(&mut self, phrase: &Phrase) -> bool {
+        match self.state.iter().position(|p| p == phrase) {
+            Some(idx) => {
+                self.state.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the phrases matched by a pattern, as a rule with the pattern as
+    /// its inputs and no outputs would, until the pattern no longer matches.
+    /// Returns the number of phrases removed.
+    pub fn retract_matching(&mut self, pattern: &Pattern) -> usize {
+        let mut removed = 0;
+
+        loop {
+            let mut first_match = None;
+
+            find_all_matches(&pattern.rule, &self.state, &mut |states_matched, _| {
+                if first_match.is_none() {
+                    first_match = Some(states_matched.to_vec());
+                }
+            });
+
+            // a pattern that matches without any phrases, such as `!a`,
+            // would otherwise match forever
+            let mut states_matched = match first_match {
+                Some(ref states_matched) if states_matched.is_empty() => break,
+                Some(states_matched) => states_matched,
+                None => break,
+            };
+
+            // remove from the end, so that the remaining indices stay valid
+            states_matched.sort();
+            for &s_i in states_matched.iter().rev() {
+                self.state.swap_remove(s_i);
+            }
+
+            removed += states_matched.len();
+        }
+
+        removed
+    }
+
+    /// The state, the random number generator, the order the rules were last
+    /// shuffled into and whether the rules are quiescent, as text that can be
+    /// read back with `load_text`, so that a context built from the same
+    /// program carries on exactly as this one would have. The state is written
+    /// as initial state in a program, one phrase per line. Fails if a phrase
+    /// starts with `type` or `#include`, which would be read back as a
+    /// statement.
+    pub fn save_text(&self) -> io::Result<String> {
+        save::to_text(
+            &self.state,
+            self.rng.state(),
+            &self.rule_ids(),
+            self.quiescence,
+            &self.string_cache,
+        )
+    }
+
+    /// Like `save_text`, in a compact binary format.
+    pub fn save_binary(&self) -> Vec<u8> {
+        save::to_binary(
+            &self.state,
+            self.rng.state(),
+            &self.rule_ids(),
+            self.quiescence,
+            &self.string_cache,
+        )
+    }
+
+    /// Replaces the state with one saved by `save_text`. The rule order is
+    /// only restored if the context has the same rules as the saved context,
+    /// and a text state without an `// rng` line keeps the current random
+    /// number generator. The phrases are checked against the `type`
+    /// declarations, as `append_state` checks them.
+    pub fn load_text(&mut self, text: &str) -> Result<(), LoadError> {
+        let snapshot = save::from_text(text, &self.types, &mut self.string_cache)?;
+        self.restore(snapshot);
+
+        Ok(())
+    }
+
+    pub fn load_binary(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        let snapshot = save::from_binary(bytes, &self.types, &mut self.string_cache)?;
+        self.restore(snapshot);
+
+        Ok(())
+    }
+
+    /// Writes `save_text` or `save_binary` to a file, to read back with `load`.
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: SaveFormat) -> io::Result<()> {
+        match format {
+            SaveFormat::Text => fs::write(path, self.save_text()?),
+            SaveFormat::Binary => fs::write(path, self.save_binary()),
+        }
+    }
+
+    /// Loads a file written by `save` in either format.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        let bytes = fs::read(path)?;
+
+        if save::is_binary(&bytes) {
+            return self.load_binary(&bytes);
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(text) => self.load_text(&text),
+            Err(_) => Err(LoadError::Invalid("not a text or binary save")),
+        }
+    }
+
+    fn rule_ids(&self) -> Vec<i32> {
+        self.rules.iter().map(|r| r.id).collect()
+    }
+
+    fn restore(&mut self, snapshot: save::Snapshot) {
+        self.state.clear();
+        for phrase in snapshot.phrases.into_iter() {
+            self.state.push(phrase);
+        }
+
+        if let Some(rng) = snapshot.rng {
+            self.rng = XorShiftRng::from_state(rng);
+        }
+
+        if let Some(rule_ids) = snapshot.rule_ids {
+            let positions = rule_ids
+                .iter()
+                .enumerate()
+                .map(|(i, &id)| (id, i))
+                .collect::<HashMap<_, _>>();
+
+            let is_same_rules = positions.len() == self.rules.len()
+                && self.rules.iter().all(|r| positions.contains_key(&r.id));
+
+            if is_same_rules {
+                self.rules.sort_by_key(|r| positions[&r.id]);
+            }
+        }
+
+        self.quiescence = snapshot.quiescence;
+    }
+
+    pub fn print(&self) {
+        println!("state:");
+        print_state(&self.state, &self.string_cache);
+
+        println!("\nrules:");
+        let mut rules = self
+            .rules
+            .iter()
+            .map(|r| rule_to_string(r, &self.string_cache))
+            .collect::<Vec<_>>();
+        rules.sort();
+
+        for r in rules {
+            println!("{}", r);
+        }
+    }
+
+    /// Parses a pattern for `find` and `retract_matching`. A pattern is written
+    /// like the inputs of a rule, such as `note INSTRUMENT POS first . !muted
+    /// INSTRUMENT`, and each phrase must match a different state. Side
+    /// predicates never match.
+    pub fn pattern(&mut self, text: &str) -> Result<Pattern, ParseError> {
+        let inputs = parser::parse_pattern(text, &self.types, &mut self.string_cache)?;
+
+        Ok(Pattern {
+            rule: Rule::new_with_id(-1, inputs, vec![]),
+        })
+    }
+
+    /// The bindings of the pattern's variables for each way that the pattern
+    /// matches the state, trying states in order.
+    pub fn find(&self, pattern: &Pattern) -> vec::IntoIter<Bindings> {
+        let string_cache = &self.string_cache;
+        let mut results = vec![];
+
+        find_all_matches(&pattern.rule, &self.state, &mut |_, variables| {
+            results.push(Bindings {
+                values: variables
+                    .into_iter()
+                    .map(|(var, value)| (string_cache.from_atom(var).into_owned(), value))
+                    .collect(),
+            });
+        });
+
+        results.into_iter()
+    }
+
+    /// Like `find`, but converts the bindings of each match of `T::pattern()`
+    /// to a `T`. The pattern is parsed the first time each type is queried.
+    pub fn query<T>(&mut self) -> Result<vec::IntoIter<T>, QueryError>
+    where
+        T: FromPhrase + 'static,
+    {
+        let id = TypeId::of::<T>();
+
+        if !self.query_patterns.contains_key(&id) {
+            let pattern = self.pattern(&T::pattern())?;
+            self.query_patterns.insert(id, pattern);
+        }
+
+        let pattern = &self.query_patterns[&id];
+        let string_cache = &self.string_cache;
+
+        self.find(pattern)
+            .map(|bindings| T::from_bindings(&bindings, string_cache))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|results| results.into_iter())
+            .map_err(QueryError::from)
+    }
+}
+
+/// A pattern parsed by `Context::pattern`, which can be matched against the
+/// state any number of times.
+#[derive(Debug)]
+pub struct Pattern {
+    // a rule with the pattern as its inputs
+    rule: Rule,
+}
+
+/// The values bound to a pattern's variables by one match of `Context::find`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bindings {
+    values: Vec<(String, Term)>,
+}
+
+impl Bindings {
+    pub fn get(&self, var: &str) -> Option<&Term> {
+        self.values
+            .iter()
+            .find(|(name, _)| name == var)
+            .map(|(_, value)| value)
+    }
+
+    /// The value of a variable bound to an atom rather than a list.
+    pub fn atom(&self, var: &str) -> Option<Atom> {
+        self.get(var).and_then(Term::as_atom)
+    }
+
+    pub fn int(&self, var: &str) -> Option<i64> {
+        self.atom(var).and_then(|atom| atom.as_int())
+    }
+
+    pub fn float(&self, var: &str) -> Option<f64> {
+        self.atom(var).and_then(|atom| atom.as_float())
+    }
+
+    /// The value of a variable converted to a `T`, such as an `i32` or a `String`.
+    pub fn value<T>(&self, var: &str, string_cache: &StringCache) -> Result<T, FromPhraseError>
+    where
+        T: FromTerm,
+    {
+        query::convert(var, self.get(var), string_cache)
+    }
+
+    /// The variables and their values, in the order they were bound.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Term)> {
+        self.values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+}
+
+/// A rule that fired, and the phrases it replaced.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Firing {
+    pub rule_id: i32,
+    /// Where the rule was written.
+    pub span: Span,
+    /// The value bound to each of the rule's variables.
+    pub bindings: Vec<(Atom, Term)>,
+    /// The phrases matched by the rule's inputs, in the order of the inputs.
+    pub removed: Vec<Phrase>,
+    pub added: Vec<Phrase>,
+}
+
+/// How a call to `update_with_limit` finished.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UpdateOutcome {
+    /// No more rules match.
+    Quiescent,
+    /// The maximum number of rules fired, and another rule would have fired.
+    StepLimitReached,
+    /// The state returned to an earlier state. `rule_ids` are the rules that
+    /// fired in between, in the order they fired.
+    CycleDetected { rule_ids: Vec<i32> },
+}
+
+pub fn update<F>(context: &mut Context, side_input: F)
+where
+    F: SideInput,
+{
+    run_update(context, None, side_input);
+}
+
+/// Like `update`, but fires at most `max_steps` rules, and stops if the state
+/// returns to one it was in earlier in the same call. Rules that choose at
+/// random can revisit a state and still finish, so a detected cycle means
+/// that the rules can loop forever, not that they will.
+pub fn update_with_limit<F>(context: &mut Context, max_steps: usize, side_input: F) -> UpdateOutcome
+where
+    F: SideInput,
+{
+    run_update(context, Some(max_steps), side_input)
+}
+
+/// Fires at most one rule, returning what it did, or None if no rule matches.
+pub fn step<F>(context: &mut Context, mut side_input: F) -> Option<Firing>
+where
+    F: SideInput,
+{
+    next_match(context, MatchMode::Fire, &mut side_input).map(|m| fire(context, m))
+}
+
+fn run_update<F>(
+    context: &mut Context,
+    max_steps: Option<usize>,
+    mut side_input: F,
+) -> UpdateOutcome
+where
+    F: SideInput,
+{
+    // with a limit, the hash of the state after each firing, the numbers of
+    // rules fired before each hash was seen, and the rules fired with the
+    // phrases that each removed and added
+    let mut state_hash = 0u64;
+    let mut seen = HashMap::new();
+    let mut fired = vec![];
+    let mut changes = vec![];
+
+    if max_steps.is_some() {
+        for p in context.state.iter() {
+            state_hash = state_hash.wrapping_add(phrase_hash(p));
+        }
+
+        seen.insert(state_hash, vec![0]);
+    }
+
+    loop {
+        // at the limit, only check whether another rule would fire, so that
+        // its output side predicates aren't evaluated
+        let mode = if max_steps == Some(fired.len()) {
+            MatchMode::MatchOnly
+        } else {
+            MatchMode::Fire
+        };
+
+        let m = match next_match(context, mode, &mut side_input) {
+            Some(m) => m,
+            None => return UpdateOutcome::Quiescent,
+        };
+
+        if mode == MatchMode::MatchOnly {
+            // leave the state as it was before the match, so that another
+            // update carries on from here
+            if m.was_quiescent {
+                let idx = context.state.len() - 1;
+                context.state.swap_remove(idx);
+                context.quiescence = true;
+            }
+
+            return UpdateOutcome::StepLimitReached;
+        }
+
+        let was_quiescent = m.was_quiescent;
+        let firing = fire(context, m);
+
+        if max_steps.is_some() {
+            let mut added = firing.added;
+
+            // the rule consumed the quiescence marker added to match it
+            if was_quiescent {
+                added.push(qui(&mut context.string_cache));
+            }
+
+            for p in firing.removed.iter() {
+                state_hash = state_hash.wrapping_sub(phrase_hash(p));
+            }
+
+            for p in added.iter() {
+                state_hash = state_hash.wrapping_add(phrase_hash(p));
+            }
+
+            fired.push(firing.rule_id);
+            changes.push((firing.removed, added));
+
+            // different states can have the same hash, so check that the
+            // firings since the hash was seen left the state as it was
+            let steps = seen.entry(state_hash).or_default();
+
+            if let Some(&step) = steps.iter().find(|&&step| undone(&changes[step..])) {
+                return UpdateOutcome::CycleDetected {
+                    rule_ids: fired[step..].to_vec(),
+                };
+            }
+
+            steps.push(fired.len());
+        }
+    }
+}
+
+// Whether the phrases removed and added by a run of firings cancel out, so
+// that the state after them is the state before them. This only looks at the
+// phrases that changed, rather than at the whole state.
+fn undone(changes: &[(Vec<Phrase>, Vec<Phrase>)]) -> bool {
+    // the net number of times each phrase was added, grouped by hash
+    let mut counts = HashMap::<u64, Vec<(&Phrase, isize)>>::new();
+
+    for (removed, added) in changes.iter() {
+        let changed = removed
+            .iter()
+            .map(|p| (p, -1))
+            .chain(added.iter().map(|p| (p, 1)));
+
+        for (p, n) in changed {
+            let phrases = counts.entry(phrase_hash(p)).or_default();
+
+            match phrases.iter_mut().find(|c| c.0 == p) {
+                Some(c) => c.1 += n,
+                None => phrases.push((p, n)),
+            }
+        }
+    }
+
+    counts.values().flatten().all(|c| c.1 == 0)
+}
+
+// Checks a phrase that didn't come from parsing, before it is added to the
+// state. An error is reported at line 1, column 1.
+pub(crate) fn check_state_phrase(
+    phrase: &Phrase,
+    types: &Types,
+    string_cache: &StringCache,
+) -> Result<(), ParseError> {
+    if phrase.is_empty() {
+        return Err(ParseError::unparsed(String::new(), "empty phrase"));
+    }
+
+    if is_quiescence(phrase, string_cache) {
+        let text = phrase_to_string(phrase, string_cache);
+        return Err(ParseError::unparsed(text, parser::QUIESCENCE_IN_STATE));
+    }
+
+    types::check_phrase(phrase, types, string_cache)
+        .map_err(|e| ParseError::unparsed(e.text, &e.reason))
+}
+
+pub(crate) fn is_quiescence(phrase: &Phrase, string_cache: &StringCache) -> bool {
+    match phrase.as_slice() {
+        [Term::Atom(ref token)] => {
+            !token.is_negated && string_cache.to_existing_atom("qui") == Some(token.string)
+        }
+        _ => false,
+    }
+}
+
+fn qui(string_cache: &mut StringCache) -> Phrase {
+    vec![Term::Atom(Token::new("qui", string_cache))]
+}
+
+fn matching_rule_ids<F>(rules: &[Rule], state: &State, side_input: &mut F) -> Vec<i32>
+where
+    F: SideInput,
+{
+    rules
+        .iter()
+        .filter(|r| rule_matches_state(r, state, 0, MatchMode::MatchOnly, side_input).is_some())
+        .map(|r| r.id)
+        .collect()
+}
+
+// Whether a match is for a rule that is about to fire, or only checks that the
+// rule matches. Output side predicates are only evaluated for a rule that
+// fires, since they call the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum MatchMode {
+    Fire,
+    MatchOnly,
+}
+
+// A rule that matched the state, with its variables resolved.
+struct PendingMatch {
+    rule: Rule,
+    variables: Vec<Match>,
+    states_matched: Vec<usize>,
+    // whether `()` was added to the state for the match
+    was_quiescent: bool,
+}
+
+// Finds the next rule to fire. If no rule matches, tries again with `()` in
+// the state, and returns None if still no rule matches.
+fn next_match<F>(context: &mut Context, mode: MatchMode, side_input: &mut F) -> Option<PendingMatch>
+where
+    F: SideInput,
+{
+    let rules = &mut context.rules;
+    let state = &mut context.state;
+
+    let qui = qui(&mut context.string_cache);
+
+    loop {
+        let mut matching_rule = None;
+
+        match context.rule_order {
+            // shuffle rules so that each has a chance of selection proportional to its weight.
+            RuleOrder::Random => shuffle_rules(&mut context.rng, rules),
+            RuleOrder::Source => rules.sort_by_key(|r| r.id),
+            RuleOrder::Priority => {
+                // the sort is stable, so rules with equal priority stay shuffled.
+                shuffle_rules(&mut context.rng, rules);
+                rules.sort_by_key(|r| Reverse(r.priority));
+            }
+        }
+
+        // the order in which a rule's potential matches are tried is derived
+        // from this seed, so that it doesn't depend on which matcher is used.
+        let seed = context.rng.gen::<u64>();
+
+        let was_quiescent = context.quiescence;
+
+        if was_quiescent {
+            state.push(qui.clone());
+        }
+
+        if let Some(ref mut rete) = context.rete {
+            rete.sync(state);
+        }
+
+        for rule in rules.iter() {
+            // early exit if rule stages can't match state
+            if !rule
+                .inputs
+                .iter()
+                .filter(|p| first_token(p).is_some_and(|t| t.is_stage && !t.is_negated))
+                .all(|input| {
+                    state
+                        .candidates(input)
+                        .iter()
+                        .any(|&s_i| test_match_without_variables(input, &state[s_i]))
+                })
+            {
+                continue;
+            }
+
+            let rule_seed = mix_seed(seed, rule.id as u64);
+
+            let result = match context.rete {
+                Some(ref rete) => rete.rule_matches_state(rule, state, rule_seed, mode, side_input),
+                None => rule_matches_state(rule, state, rule_seed, mode, side_input),
+            };
+
+            if let Some(result) = result {
+                matching_rule = Some(result);
+                break;
+            }
+        }
+
+        if was_quiescent {
+            context.quiescence = false;
+
+            if matching_rule.is_none() {
+                assert!(
+                    state
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, p)| **p == qui[..])
+                        .collect::<Vec<_>>()
+                        == vec![(state.len() - 1, &qui.clone())],
+                    "expected 1 * () at end of state"
+                );
+
+                let idx = state.len() - 1;
+                state.swap_remove(idx);
+
+                return None;
+            }
+        }
+
+        match matching_rule {
+            Some((rule, variables, states_matched)) => {
+                return Some(PendingMatch {
+                    rule,
+                    variables,
+                    states_matched,
+                    was_quiescent,
+                })
+            }
+            None => context.quiescence = true,
+        }
+    }
+}
+
+// Replaces the states matched by a rule with its outputs.
+fn fire(context: &mut Context, m: PendingMatch) -> Firing {
+    let PendingMatch {
+        rule,
+        variables,
+        mut states_matched,
+        ..
+    } = m;
+
+    let state = &mut context.state;
+
+    let removed = states_matched
+        .iter()
+        .map(|&s_i| state[s_i].clone())
+        .collect::<Vec<_>>();
+
+    // remove from the end, so that the remaining indices stay valid
+    states_matched.sort();
+    for &s_i in states_matched.iter().rev() {
+        state.swap_remove(s_i);
+    }
+
+    for output in rule.outputs.iter() {
+        state.push(output.clone());
+    }
+
+    let firing = Firing {
+        rule_id: rule.id,
+        span: rule.span,
+        bindings: variables,
+        removed,
+        added: rule.outputs,
+    };
+
+    if let Some(ref mut trace) = context.trace {
+        trace(&firing, &context.string_cache);
+    }
+
+    firing
+}
+
+// Calls `found` with the states matched by the rule's inputs and the bound
+// variables, for each way that the rule's inputs match the state.
+fn find_all_matches<T>(r: &Rule, state: &State, found: &mut T)
+where
+    T: FnMut(&[usize], Vec<Match>),
+{
+    let rule_inputs = RuleInputs::new(&r.inputs);
+
+    find_all_matches_from(r, state, &rule_inputs, &mut vec![], &[], found);
+}
+
+fn find_all_matches_from<T>(
+    r: &Rule,
+    state: &State,
+    rule_inputs: &RuleInputs,
+    states_matched: &mut Vec<usize>,
+    variables_matched: &[Match],
+    found: &mut T,
+) where
+    T: FnMut(&[usize], Vec<Match>),
+{
+    let level = states_matched.len();
+
+    if level == rule_inputs.forward_pred.len() {
+        if let Some((_, variables)) = complete_match(
+            r,
+            rule_inputs,
+            state,
+            states_matched,
+            variables_matched,
+            MatchMode::MatchOnly,
+            &mut |_: &Phrase| None,
+        ) {
+            found(states_matched, variables);
+        }
+
+        return;
+    }
+
+    let input = &r.inputs[rule_inputs.forward_pred[level]];
+
+    let mut candidates = state.candidates(input).into_owned();
+    candidates.sort();
+
+    for &s_i in candidates.iter() {
+        if states_matched.contains(&s_i) || !test_match_without_variables(input, &state[s_i]) {
+            continue;
+        }
+
+        if let Some(mut result) =
+            match_variables_with_existing(input, &state[s_i], variables_matched)
+        {
+            let mut variables = variables_matched.to_vec();
+            variables.append(&mut result);
+
+            states_matched.push(s_i);
+            find_all_matches_from(r, state, rule_inputs, states_matched, &variables, found);
+            states_matched.pop();
+        }
+    }
+}
+
+// A hash of the parts of a phrase compared by `Token::eq`. The hashes of a
+// state's phrases are summed, so that the order of the state doesn't matter.
+fn phrase_hash(phrase: &Phrase) -> u64 {
+    fn hash_term(term: &Term, hasher: &mut DefaultHasher) {
+        match *term {
+            Term::Atom(ref t) => (0u8, t.string, t.is_side).hash(hasher),
+            Term::List(ref terms) => {
+                (1u8, terms.len()).hash(hasher);
+                terms.iter().for_each(|t| hash_term(t, hasher));
+            }
+            Term::Cons(ref terms, ref tail) => {
+                (2u8, terms.len()).hash(hasher);
+                terms.iter().for_each(|t| hash_term(t, hasher));
+                hash_term(tail, hasher);
+            }
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    phrase.len().hash(&mut hasher);
+
+    for t in phrase.iter() {
+        hash_term(t, &mut hasher);
+    }
+
+    hasher.finish()
+}
+
+// The indices of a rule's inputs, by kind of predicate.
+struct RuleInputs {
+    forward_pred: Vec<usize>,
+    backwards_pred: Vec<usize>,
+    side_pred: Vec<usize>,
+    negated_pred: Vec<usize>,
+}
+
+impl RuleInputs {
+    fn new(inputs: &[Phrase]) -> RuleInputs {
+        let mut forward_pred = vec![];
+        let mut backwards_pred = vec![];
+        let mut side_pred = vec![];
+        let mut negated_pred = vec![];
+
+        for (i_i, input) in inputs.iter().enumerate() {
+            if is_backwards_pred(input) {
+                backwards_pred.push(i_i);
+            } else if is_side_pred(input) {
+                // TODO: exit early if we already know that side predicate won't match
+                side_pred.push(i_i);
+            } else if is_negated_pred(input) {
+                negated_pred.push(i_i);
+            } else {
+                forward_pred.push(i_i);
+            }
+        }
+
+        RuleInputs {
+            forward_pred,
+            backwards_pred,
+            side_pred,
+            negated_pred,
+        }
+    }
+}
+
+// Checks whether the rule's forward and backward predicates match the state.
+// Returns a new rule with all variables resolved, with backwards/side
+// predicates removed, along with the variables and the indices of the states
+// matched by the rule's forward predicates.
+fn rule_matches_state<F>(
+    r: &Rule,
+    state: &State,
+    seed: u64,
+    mode: MatchMode,
+    side_input: &mut F,
+) -> Option<(Rule, Vec<Match>, Vec<usize>)>
+where
+    F: SideInput,
+{
+    let inputs = &r.inputs;
+
+    let rule_inputs = RuleInputs::new(inputs);
+    let forward_pred = &rule_inputs.forward_pred;
+
+    // per forward input, the states that could match the input.
+    let mut candidates = Vec::with_capacity(forward_pred.len());
+    for &i_i in forward_pred.iter() {
+        let input_candidates = state.candidates(&inputs[i_i]);
+
+        if input_candidates.is_empty() {
+            return None;
+        }
+
+        candidates.push(input_candidates);
+    }
+
+    // depth-first search across the tree of permutations, where each level of
+    // the tree is a forward input, and each branch is a match against a state.
+    // branches are visited in a random order, so that a rule with multiple
+    // potential matches does not always match the same permutation of state.
+    let mut branches = vec![];
+    let mut states_matched = vec![];
+    let mut variables_matched = vec![];
+    let mut variables_matched_lens = vec![];
+
+    if let Some(c) = candidates.first() {
+        branches.push(c.iter_rand(&mut branch_rng(seed, &states_matched)));
+    }
+
+    loop {
+        let level = states_matched.len();
+
+        if level == forward_pred.len() {
+            if let Some((rule, variables)) = complete_match(
+                r,
+                &rule_inputs,
+                state,
+                &states_matched,
+                &variables_matched,
+                mode,
+                side_input,
+            ) {
+                return Some((rule, variables, states_matched));
+            }
+
+            if level == 0 {
+                return None;
+            }
+
+            states_matched.pop();
+            variables_matched.truncate(variables_matched_lens.pop().expect("len"));
+            continue;
+        }
+
+        match branches[level].next() {
+            Some((_, &s_i)) => {
+                // a previous input in this permutation has already matched the state being checked
+                if states_matched.contains(&s_i) {
+                    continue;
+                }
+
+                let input = &inputs[forward_pred[level]];
+
+                if !test_match_without_variables(input, &state[s_i]) {
+                    continue;
+                }
+
+                if let Some(ref mut result) =
+                    match_variables_with_existing(input, &state[s_i], &variables_matched)
+                {
+                    variables_matched_lens.push(variables_matched.len());
+                    variables_matched.append(result);
+                    states_matched.push(s_i);
+
+                    if level + 1 < forward_pred.len() {
+                        let mut rng = branch_rng(seed, &states_matched);
+                        branches.push(candidates[level + 1].iter_rand(&mut rng));
+                    }
+                }
+            }
+            None => {
+                branches.pop();
+
+                if level == 0 {
+                    return None;
+                }
+
+                states_matched.pop();
+                variables_matched.truncate(variables_matched_lens.pop().expect("len"));
+            }
+        }
+    }
+}
+
+// Checks the backwards, side and negated predicates of a rule whose forward
+// predicates have matched, returning the rule with all variables resolved,
+// and the variables.
+fn complete_match<F>(
+    r: &Rule,
+    rule_inputs: &RuleInputs,
+    state: &State,
+    states_matched: &[usize],
+    variables_matched: &[Match],
+    mode: MatchMode,
+    side_input: &mut F,
+) -> Option<(Rule, Vec<Match>)>
+where
+    F: SideInput,
+{
+    let inputs = &r.inputs;
+    let outputs = &r.outputs;
+
+    let mut variables_matched = variables_matched.to_vec();
+
+    for input in rule_inputs.backwards_pred.iter().map(|&i| &inputs[i]) {
+        let mut extra_matches = match_backwards_variables(input, &variables_matched);
+
+        if let Some(ref mut extra_matches) = extra_matches {
+            variables_matched.append(extra_matches);
+        } else {
+            return None;
+        }
+    }
+
+    for input in rule_inputs.side_pred.iter().map(|&i| &inputs[i]) {
+        let mut extra_matches = match_side_variables(input, &variables_matched, side_input);
+
+        if let Some(ref mut extra_matches) = extra_matches {
+            variables_matched.append(extra_matches);
+        } else {
+            return None;
+        }
+    }
+
+    for input in rule_inputs.negated_pred.iter().map(|&i| &inputs[i]) {
+        // check negated predicates last, so that we know about all variables
+        // from the backwards and side predicates
+        if state
+            .candidates(input)
+            .iter()
+            .filter(|s_i| !states_matched.contains(s_i))
+            .any(|&s_i| {
+                match_variables_with_existing(input, &state[s_i], &variables_matched).is_some()
+            })
+        {
+            return None;
+        }
+    }
+
+    let mut forward_concrete = vec![];
+    let mut outputs_concrete = vec![];
+
+    for v in inputs.iter() {
+        if !is_backwards_pred(v) && !is_side_pred(v) && !is_negated_pred(v) {
+            forward_concrete.push(assign_vars(v, &variables_matched));
+        }
+    }
+
+    for v in outputs.iter() {
+        if is_side_pred(v) {
+            if mode == MatchMode::Fire {
+                let pred = assign_vars(v, &variables_matched);
+
+                evaluate_side_pred(&pred, side_input);
+            }
+        } else {
+            outputs_concrete.push(assign_vars(v, &variables_matched));
+        }
+    }
+
+    let mut rule = Rule::new_with_id(r.id, forward_concrete, outputs_concrete);
+    rule.span = r.span.clone();
+
+    Some((rule, variables_matched))
+}
+
+fn match_backwards_variables(pred: &Phrase, existing_matches: &[Match]) -> Option<Vec<Match>> {
+    let pred = assign_vars(pred, existing_matches);
+
+    evaluate_backwards_pred(&pred).and_then(|eval_result| {
+        match_variables_with_existing(&pred, &eval_result, existing_matches)
+    })
+}
+
+fn match_side_variables<F>(
+    pred: &Phrase,
+    existing_matches: &[Match],
+    side_input: &mut F,
+) -> Option<Vec<Match>>
+where
+    F: SideInput,
+{
+    let pred = assign_vars(pred, existing_matches);
+
+    evaluate_side_pred(&pred, side_input).and_then(|eval_result| {
+        match_variables_with_existing(&pred, &eval_result, existing_matches)
+    })
+}
+
+fn assign_vars(tokens: &Phrase, matches: &[Match]) -> Phrase {
+    // a phrase of a single variable matches a whole phrase, so a variable
+    // bound to a list becomes the terms of the list.
+    if let [Term::Atom(ref token)] = tokens[..] {
+        if let Some(terms) = bound_value(token, matches).and_then(Term::as_list) {
+            let mut result = terms.to_vec();
+
+            if let Some(first) = result.first_mut() {
+                match *first {
+                    Term::Atom(ref mut first) => first.is_negated = token.is_negated,
+                    Term::List(_) | Term::Cons(..) => (),
+                }
+            }
+
+            return result;
+        }
+    }
+
+    tokens
+        .iter()
+        .map(|t| assign_term_vars(t, matches))
+        .collect()
+}
+
+fn assign_term_vars(term: &Term, matches: &[Match]) -> Term {
+    match *term {
+        Term::Atom(ref token) => match bound_value(token, matches) {
+            Some(value) => {
+                let mut value = value.clone();
+
+                if let Term::Atom(ref mut v) = value {
+                    v.is_negated = token.is_negated;
+                }
+
+                value
+            }
+            None => term.clone(),
+        },
+        Term::List(ref terms) => {
+            Term::List(terms.iter().map(|t| assign_term_vars(t, matches)).collect())
+        }
+        Term::Cons(ref terms, ref tail) => {
+            let mut terms = terms
+                .iter()
+                .map(|t| assign_term_vars(t, matches))
+                .collect::<Vec<_>>();
+
+            match assign_term_vars(tail, matches) {
+                Term::List(mut rest) => {
+                    terms.append(&mut rest);
+                    Term::List(terms)
+                }
+                // the tail isn't bound to a list yet
+                tail => Term::Cons(terms, Box::new(tail)),
+            }
+        }
+    }
+}
+
+// The value of `token` if it is a bound variable.
+fn bound_value<'a>(token: &Token, matches: &'a [Match]) -> Option<&'a Term> {
+    if !token.is_var {
+        return None;
+    }
+
+    matches.iter().find(|m| m.0 == token.string).map(|m| &m.1)
+}
+
+fn is_backwards_pred(tokens: &Phrase) -> bool {
+    first_token(tokens).is_some_and(|t| t.backwards_pred.is_some())
+}
+
+fn is_side_pred(tokens: &Phrase) -> bool {
+    first_token(tokens).is_some_and(|t| t.is_side)
+}
+
+fn is_negated_pred(tokens: &Phrase) -> bool {
+    first_token(tokens).is_some_and(|t| t.is_negated)
+}
+
+// Evaluates a backwards predicate whose bound variables have been assigned.
+// Returns the predicate with its remaining variables solved, or None if it
+// can't be satisfied.
+fn evaluate_backwards_pred(tokens: &Phrase) -> Option<Phrase> {
+    let number = |i: usize| tokens[i].as_number();
+
+    match first_token(tokens).and_then(|t| t.backwards_pred.clone()) {
+        Some(BackwardsPred::Plus) => solve_binary(
+            tokens,
+            |v1, v2| v1.add(v2),
+            |v2, v3| v3.sub(v2),
+            |v1, v3| v3.sub(v1),
+        ),
+        Some(BackwardsPred::Minus) => solve_binary(
+            tokens,
+            |v1, v2| v1.sub(v2),
+            |v2, v3| v3.add(v2),
+            |v1, v3| v1.sub(v3),
+        ),
+        Some(BackwardsPred::Times) => solve_binary(
+            tokens,
+            |v1, v2| v1.mul(v2),
+            |v2, v3| v3.div(v2),
+            |v1, v3| v3.div(v1),
+        ),
+        Some(BackwardsPred::Divide) => solve_binary(
+            tokens,
+            |v1, v2| v1.div(v2),
+            |v2, v3| v3.mul(v2),
+            |v1, v3| v1.div(v3),
+        ),
+        Some(BackwardsPred::IntDivide) => {
+            solve_binary(tokens, |v1, v2| v1.floor_div(v2), |_, _| None, |_, _| None)
+        }
+        Some(BackwardsPred::Min) => {
+            solve_binary(tokens, |v1, v2| v1.min(v2), |_, _| None, |_, _| None)
+        }
+        Some(BackwardsPred::Max) => {
+            solve_binary(tokens, |v1, v2| v1.max(v2), |_, _| None, |_, _| None)
+        }
+        Some(BackwardsPred::Abs) => {
+            if tokens.len() != 3 {
+                return None;
+            }
+
+            match (number(1), number(2)) {
+                (Some(v1), None) => v1.abs().map(|v2| {
+                    let mut result = tokens.clone();
+                    result[2] = Term::number(v2);
+                    result
+                }),
+                (Some(v1), Some(v2)) if v1.abs() == Some(v2) => Some(tokens.clone()),
+                _ => None,
+            }
+        }
+        Some(BackwardsPred::Eq) => {
+            if tokens.len() != 3 {
+                return None;
+            }
+
+            // any terms can be compared, so unbound arguments are variables
+            // rather than anything that isn't a number.
+            let mut result = tokens.clone();
+
+            match (tokens[1].is_var(), tokens[2].is_var()) {
+                (false, true) => result[2] = tokens[1].clone(),
+                (true, false) => result[1] = tokens[2].clone(),
+                (false, false) if tokens[1] == tokens[2] => {}
+                _ => return None,
+            }
+
+            Some(result)
+        }
+        Some(BackwardsPred::Neq) => {
+            if tokens.len() != 3 {
+                return None;
+            }
+
+            match (tokens[1].is_var(), tokens[2].is_var()) {
+                (false, false) if tokens[1] != tokens[2] => Some(tokens.clone()),
+                _ => None,
+            }
+        }
+        Some(BackwardsPred::Lt) => match (number(1), number(2)) {
+            (Some(v1), Some(v2)) if v1 < v2 => Some(tokens.clone()),
+            _ => None,
+        },
+        Some(BackwardsPred::Gt) => match (number(1), number(2)) {
+            (Some(v1), Some(v2)) if v1 > v2 => Some(tokens.clone()),
+            _ => None,
+        },
+        Some(BackwardsPred::Lte) => match (number(1), number(2)) {
+            (Some(v1), Some(v2)) if v1 <= v2 => Some(tokens.clone()),
+            _ => None,
+        },
+        Some(BackwardsPred::Gte) => match (number(1), number(2)) {
+            (Some(v1), Some(v2)) if v1 >= v2 => Some(tokens.clone()),
+            _ => None,
+        },
+        Some(BackwardsPred::ModNeg) => {
+            solve_binary(tokens, |v1, v2| v1.mod_neg(v2), |_, _| None, |_, _| None)
+        }
+        Some(BackwardsPred::Length) => {
+            if tokens.len() != 3 {
+                return None;
+            }
+
+            let length = Number::Int(tokens[1].as_list()?.len() as i64);
+
+            match number(2) {
+                None if tokens[2].is_var() => {
+                    let mut result = tokens.clone();
+                    result[2] = Term::number(length);
+                    Some(result)
+                }
+                Some(v2) if v2 == length => Some(tokens.clone()),
+                _ => None,
+            }
+        }
+        Some(BackwardsPred::Append) => {
+            if tokens.len() != 4 {
+                return None;
+            }
+
+            let list = |i: usize| tokens[i].as_list();
+            let mut result = tokens.clone();
+
+            match (list(1), list(2), list(3)) {
+                (Some(l1), Some(l2), None) if tokens[3].is_var() => {
+                    result[3] = Term::List(l1.iter().chain(l2.iter()).cloned().collect());
+                }
+                (Some(l1), None, Some(l3)) if tokens[2].is_var() && l3.starts_with(l1) => {
+                    result[2] = Term::List(l3[l1.len()..].to_vec());
+                }
+                (None, Some(l2), Some(l3)) if tokens[1].is_var() && l3.ends_with(l2) => {
+                    result[1] = Term::List(l3[..l3.len() - l2.len()].to_vec());
+                }
+                (Some(l1), Some(l2), Some(l3))
+                    if l3.len() == l1.len() + l2.len()
+                        && l3.starts_with(l1)
+                        && l3.ends_with(l2) => {}
+                _ => return None,
+            }
+
+            Some(result)
+        }
+        _ => None,
+    }
+}
+
+// Solves a predicate `op A B C`, where C = A op B. `c` finds C from A and B,
+// `a` finds A from B and C, and `b` finds B from A and C, returning None if
+// there is no single solution. If all three are bound, the equation is checked.
+fn solve_binary<C, A, B>(tokens: &Phrase, c: C, a: A, b: B) -> Option<Phrase>
+where
+    C: Fn(Number, Number) -> Option<Number>,
+    A: Fn(Number, Number) -> Option<Number>,
+    B: Fn(Number, Number) -> Option<Number>,
+{
+    if tokens.len() != 4 {
+        return None;
+    }
+
+    let number = |i: usize| tokens[i].as_number();
+    let mut result = tokens.clone();
+
+    match (number(1), number(2), number(3)) {
+        (Some(v1), Some(v2), None) => result[3] = Term::number(c(v1, v2)?),
+        (Some(v1), None, Some(v3)) => result[2] = Term::number(b(v1, v3)?),
+        (None, Some(v2), Some(v3)) => result[1] = Term::number(a(v2, v3)?),
+        (Some(v1), Some(v2), Some(v3)) if c(v1, v2) == Some(v3) => {}
+        _ => return None,
+    }
+
+    Some(result)
+}
+
+fn evaluate_side_pred<F>(tokens: &Phrase, side_input: &mut F) -> Option<Phrase>
+where
+    F: SideInput,
+{
+    side_input(tokens)
+}
+
+// Whether the input could match the phrase, treating each variable as
+// matching anything, even if it appears twice.
+fn test_match_without_variables(input_tokens: &Phrase, pred_tokens: &Phrase) -> bool {
+    fn test_term(input: &Term, pred: &Term) -> bool {
+        match *input {
+            Term::Atom(ref token) if token.is_var => true,
+            Term::Atom(ref token) => match *pred {
+                Term::Atom(ref pred_token) => token == pred_token,
+                _ => false,
+            },
+            Term::List(ref terms) => match *pred {
+                Term::List(ref pred_terms) => {
+                    terms.len() == pred_terms.len()
+                        && terms
+                            .iter()
+                            .zip(pred_terms.iter())
+                            .all(|(t, p)| test_term(t, p))
+                }
+                _ => false,
+            },
+            Term::Cons(ref terms, _) => match *pred {
+                Term::List(ref pred_terms) => {
+                    terms.len() <= pred_terms.len()
+                        && terms
+                            .iter()
+                            .zip(pred_terms.iter())
+                            .all(|(t, p)| test_term(t, p))
+                }
+                _ => false,
+            },
+        }
+    }
+
+    if is_whole_phrase_var(input_tokens) {
+        return true;
+    }
+
+    input_tokens.len() == pred_tokens.len()
+        && input_tokens
+            .iter()
+            .zip(pred_tokens.iter())
+            .all(|(t, p)| test_term(t, p))
+}
+
+// Whether the input is a single variable, which matches a whole phrase.
+fn is_whole_phrase_var(input_tokens: &Phrase) -> bool {
+    input_tokens.len() == 1 && input_tokens[0].is_var()
+}
+
+fn match_variables_with_existing(
+    input_tokens: &Phrase,
+    pred_tokens: &Phrase,
+    existing_matches: &[Match],
+) -> Option<Vec<Match>> {
+    let mut result = vec![];
+
+    if is_whole_phrase_var(input_tokens) && pred_tokens.len() != 1 {
+        let value = Term::List(pred_tokens.clone());
+
+        return match unify(&input_tokens[0], &value, existing_matches, &mut result) {
+            true => Some(result),
+            false => None,
+        };
+    }
+
+    if input_tokens.len() != pred_tokens.len() {
+        return None;
+    }
+
+    for (token, pred_token) in input_tokens.iter().zip(pred_tokens.iter()) {
+        if !unify(token, pred_token, existing_matches, &mut result) {
+            return None;
+        }
+    }
+
+    Some(result)
+}
+
+// Matches a term of an input against a term of a phrase, adding the values of
+// variables not already in `existing_matches` or `result` to `result`.
+fn unify(input: &Term, pred: &Term, existing_matches: &[Match], result: &mut Vec<Match>) -> bool {
+    match *input {
+        Term::Atom(ref token) if token.is_var => {
+            let existing = result
+                .iter()
+                .chain(existing_matches.iter())
+                .find(|m| m.0 == token.string)
+                .map(|m| m.1 == *pred);
+
+            match existing {
+                Some(is_equal) => is_equal,
+                None => {
+                    result.push((token.string, pred.clone()));
+                    true
+                }
+            }
+        }
+        Term::Atom(ref token) => match *pred {
+            Term::Atom(ref pred_token) => token == pred_token,
+            _ => false,
+        },
+        Term::List(ref terms) => match *pred {
+            Term::List(ref pred_terms) if terms.len() == pred_terms.len() => terms
+                .iter()
+                .zip(pred_terms.iter())
+                .all(|(t, p)| unify(t, p, existing_matches, result)),
+            _ => false,
+        },
+        Term::Cons(ref terms, ref tail) => match *pred {
+            Term::List(ref pred_terms) if terms.len() <= pred_terms.len() => {
+                let rest = Term::List(pred_terms[terms.len()..].to_vec());
+
+                terms
+                    .iter()
+                    .zip(pred_terms.iter())
+                    .all(|(t, p)| unify(t, p, existing_matches, result))
+                    && unify(tail, &rest, existing_matches, result)
+            }
+            _ => false,
+        },
+    }
+}
+
+fn rng_from_seed(seed: u64) -> XorShiftRng {
+    // xorshift's first outputs barely differ for similar seeds, so spread the
+    // seed over the state. the second half is inverted so that the state can
+    // never be all zeros.
+    let mixed = mix_seed(seed, 0);
+
+    XorShiftRng::from_state([
+        mixed as u32,
+        (mixed >> 32) as u32,
+        !mixed as u32,
+        !(mixed >> 32) as u32,
+    ])
+}
+
+// Derives a new seed from `seed` and `value`, using the finalizer from splitmix64.
+fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = (seed ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// The random order in which the candidates for the next forward input are
+// tried, after the previous inputs matched the states in `path`.
+fn branch_rng(seed: u64, path: &[usize]) -> XorShiftRng {
+    rng_from_seed(
+        path.iter()
+            .fold(seed, |seed, &s_i| mix_seed(seed, s_i as u64)),
+    )
+}
+
+// Rng::shuffle samples usize values, which produces different sequences on
+// 32-bit and 64-bit targets. sample u32 values instead, so that a seeded
+// context behaves the same on every platform.
+fn shuffle<T, R: Rng>(rng: &mut R, values: &mut [T]) {
+    let mut i = values.len();
+    while i >= 2 {
+        i -= 1;
+        values.swap(i, rng.gen_range(0, i as u32 + 1) as usize);
+    }
+}
+
+// Orders rules so that each position is filled by one of the remaining rules,
+// chosen with a probability proportional to its weight. The first rule of any
+// subset, such as the rules that match, is then also chosen in proportion to
+// weight. Rules with equal weights use a plain shuffle.
+fn shuffle_rules<R: Rng>(rng: &mut R, rules: &mut [Rule]) {
+    if rules.iter().all(|r| r.weight == rules[0].weight) {
+        shuffle(rng, rules);
+        return;
+    }
+
+    for i in 0..rules.len() {
+        let total = rules[i..].iter().map(|r| r.weight as u64).sum::<u64>();
+        let mut n = rng.gen_range(0, total);
+
+        for j in i..rules.len() {
+            if n < rules[j].weight as u64 {
+                rules.swap(i, j);
+                break;
+            }
+
+            n -= rules[j].weight as u64;
+        }
+    }
+}
+
+fn random_prime<R: Rng>(rng: &mut R) -> usize {
+    #[rustfmt::skip]
+  let primes = [
+    2,    3,    5,    7,    11,   13,   17,   19,   23,   29,   31,   37,
+    41,   43,   47,   53,   59,   61,   67,   71,   73,   79,   83,   89,
+    97,   101,  103,  107,  109,  113,  127,  131,  137,  139,  149,  151,
+    157,  163,  167,  173,  179,  181,  191,  193,  197,  199,  211,  223,
+    227,  229,  233,  239,  241,  251,  257,  263,  269,  271,  277,  281,
+    283,  293,  307,  311,  313,  317,  331,  337,  347,  349,  353,  359,
+    367,  373,  379,  383,  389,  397,  401,  409,  419,  421,  431,  433,
+    439,  443,  449,  457,  461,  463,  467,  479,  487,  491,  499,  503,
+    509,  521,  523,  541,  547,  557,  563,  569,  571,  577,  587,  593,
+    599,  601,  607,  613,  617,  619,  631,  641,  643,  647,  653,  659,
+    661,  673,  677,  683,  691,  701,  709,  719,  727,  733,  739,  743,
+    751,  757,  761,  769,  773,  787,  797,  809,  811,  821,  823,  827,
+    829,  839,  853,  857,  859,  863,  877,  881,  883,  887,  907,  911,
+    919,  929,  937,  941,  947,  953,  967,  971,  977,  983,  991,  997,
+    1009, 1013, 1019, 1021, 1031, 1033, 1039, 1049, 1051, 1061, 1063, 1069,
+    1087, 1091, 1093, 1097, 1103, 1109, 1117, 1123, 1129, 1151, 1153, 1163,
+    1171, 1181, 1187, 1193, 1201, 1213, 1217, 1223, 1229, 1231, 1237, 1249,
+    1259, 1277, 1279, 1283, 1289, 1291, 1297, 1301, 1303, 1307, 1319, 1321,
+    1327, 1361, 1367, 1373, 1381, 1399, 1409, 1423, 1427, 1429, 1433, 1439,
+    1447, 1451, 1453, 1459, 1471, 1481, 1483, 1487, 1489, 1493, 1499, 1511,
+    1523, 1531, 1543, 1549, 1553, 1559, 1567, 1571, 1579, 1583, 1597, 1601,
+    1607, 1609, 1613, 1619, 1621, 1627, 1637, 1657, 1663, 1667, 1669, 1693,
+    1697, 1699, 1709, 1721, 1723, 1733, 1741, 1747, 1753, 1759, 1777, 1783,
+    1787, 1789, 1801, 1811, 1823, 1831, 1847, 1861, 1867, 1871, 1873, 1877,
+    1879, 1889, 1901, 1907, 1913, 1931, 1933, 1949, 1951, 1973, 1979, 1987,
+    1993, 1997, 1999, 2003, 2011, 2017, 2027, 2029, 2039, 2053, 2063, 2069,
+    2081, 2083, 2087, 2089, 2099, 2111, 2113, 2129, 2131, 2137, 2141, 2143,
+    2153, 2161, 2179, 2203, 2207, 2213, 2221, 2237, 2239, 2243, 2251, 2267,
+    2269, 2273, 2281, 2287, 2293, 2297, 2309, 2311, 2333, 2339, 2341, 2347,
+    2351, 2357, 2371, 2377, 2381, 2383, 2389, 2393, 2399, 2411, 2417, 2423,
+    2437, 2441, 2447, 2459, 2467, 2473, 2477, 2503, 2521, 2531, 2539, 2543,
+    2549, 2551, 2557, 2579, 2591, 2593, 2609, 2617, 2621, 2633, 2647, 2657,
+    2659, 2663, 2671, 2677, 2683, 2687, 2689, 2693, 2699, 2707, 2711, 2713,
+    2719, 2729, 2731, 2741, 2749, 2753, 2767, 2777, 2789, 2791, 2797, 2801,
+    2803, 2819, 2833, 2837, 2843, 2851, 2857, 2861, 2879, 2887, 2897, 2903,
+    2909, 2917, 2927, 2939, 2953, 2957, 2963, 2969, 2971, 2999, 3001
+  ];
+
+    primes[rng.gen_range(0, primes.len() as u32) as usize]
+}
+
+trait IterRand {
+    type Item;
+
+    fn iter_rand<'a, R: Rng>(&'a self, rng: &mut R) -> IterRandState<'a, Self::Item>;
+}
+
+impl<T> IterRand for [T] {
+    type Item = T;
+
+    fn iter_rand<'a, R: Rng>(&'a self, rng: &mut R) -> IterRandState<'a, T> {
+        let order = RandOrder::new(self.len(), rng);
+
+        IterRandState {
+            modulo: order.start,
+            order,
+            idx: 0,
+            _slice: self,
+        }
+    }
+}
+
+impl<'a, T> iter::Iterator for IterRandState<'a, T> {
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<(usize, &'a T)> {
+        if self.idx == self.order.length {
+            None
+        } else {
+            let result = Some((self.modulo, &self._slice[self.modulo]));
+
+            self.idx += 1;
+            self.modulo = (self.modulo + self.order.rand) % self.order.length;
+
+            result
+        }
+    }
+}
+
+struct IterRandState<'a, T: 'a> {
+    order: RandOrder,
+    idx: usize,
+    modulo: usize,
+    _slice: &'a [T],
+}
+
+// A random order over the positions 0..length. The order starts from a random
+// position, then steps through the rest with a stride that is coprime to the
+// length, so that every position is visited.
+struct RandOrder {
+    rand: usize,
+    start: usize,
+    length: usize,
+    // the inverse of the stride modulo the length
+    inverse: u64,
+}
+
+impl RandOrder {
+    fn new<R: Rng>(length: usize, rng: &mut R) -> RandOrder {
+        let mut rand = 1;
+
+        if length > 1 {
+            while length.is_multiple_of(rand) || rand.is_multiple_of(length) {
+                rand = random_prime(rng);
+            }
+        }
+
+        let start = if length > 0 {
+            rng.gen_range(0, length as u32) as usize
+        } else {
+            0
+        };
+
+        let inverse = if length > 1 {
+            mod_inverse(rand as u64 % length as u64, length as u64)
+        } else {
+            0
+        };
+
+        RandOrder {
+            rand,
+            start,
+            length,
+            inverse,
+        }
+    }
+
+    // The number of positions visited before `pos`.
+    fn rank(&self, pos: usize) -> usize {
+        if self.length <= 1 {
+            return 0;
+        }
+
+        let length = self.length as u64;
+        let offset = (pos as u64 + length - self.start as u64) % length;
+
+        (offset * self.inverse % length) as usize
+    }
+}
+
+// The inverse of `a` modulo `m`, where `a` and `m` are coprime.
+fn mod_inverse(a: u64, m: u64) -> u64 {
+    let (mut r0, mut r1) = (m as i64, a as i64);
+    let (mut t0, mut t1) = (0i64, 1i64);
+
+    while r1 != 0 {
+        let q = r0 / r1;
+
+        let r = r0 - q * r1;
+        r0 = r1;
+        r1 = r;
+
+        let t = t0 - q * t1;
+        t0 = t1;
+        t1 = t;
+    }
+
+    (if t0 < 0 { t0 + m as i64 } else { t0 }) as u64
+}
+
+/// The text of a phrase, as it would be written in a program.
+pub fn phrase_to_string(phrase: &Phrase, string_cache: &StringCache) -> String {
+    build_phrase(phrase, string_cache)
+}
+
+fn build_phrase(phrase: &Phrase, string_cache: &StringCache) -> String {
+    phrase
+        .iter()
+        .map(|t| term_to_string(t, string_cache))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The text of a term, with lists in brackets.
+pub fn term_to_string(term: &Term, string_cache: &StringCache) -> String {
+    match *term {
+        Term::Atom(ref t) => format!(
+            "{}{}",
+            if t.is_negated { "!" } else { "" },
+            t.as_str(string_cache)
+        ),
+        Term::List(ref terms) => format!("({})", build_phrase(terms, string_cache)),
+        Term::Cons(ref terms, ref tail) => format!(
+            "({} | {})",
+            build_phrase(terms, string_cache),
+            term_to_string(tail, string_cache)
+        ),
+    }
+}
+
+fn print_state(state: &[Phrase], string_cache: &StringCache) {
+    for s in state
+        .iter()
+        .map(|p| build_phrase(p, string_cache))
+        .collect::<Vec<_>>()
+    {
+        println!("{}", s);
+    }
+}
+
+pub fn firing_to_string(firing: &Firing, string_cache: &StringCache) -> String {
+    let removed = firing
+        .removed
+        .iter()
+        .map(|p| build_phrase(p, string_cache))
+        .collect::<Vec<_>>()
+        .join(" . ");
+
+    let added = firing
+        .added
+        .iter()
+        .map(|p| build_phrase(p, string_cache))
+        .collect::<Vec<_>>()
+        .join(" . ");
+
+    let bindings = firing
+        .bindings
+        .iter()
+        .map(|&(var, ref value)| {
+            format!(
+                "{} = {}",
+                string_cache.from_atom(var),
+                term_to_string(value, string_cache)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "{:5}: {} = {} [{}]  // {}",
+        firing.rule_id, removed, added, bindings, firing.span
+    )
+}
+
+pub fn rule_to_string(rule: &Rule, string_cache: &StringCache) -> String {
+    let inputs = rule
+        .inputs
+        .iter()
+        .map(|p| build_phrase(p, string_cache))
+        .collect::<Vec<_>>()
+        .join(" . ");
+
+    let outputs = rule
+        .outputs
+        .iter()
+        .map(|p| build_phrase(p, string_cache))
+        .collect::<Vec<_>>()
+        .join(" . ");
+
+    let mut annotations = String::new();
+
+    if rule.priority != 0 {
+        annotations.push_str(&format!("@priority {} ", rule.priority));
+    }
+
+    if rule.weight != 1 {
+        annotations.push_str(&format!("@weight {} ", rule.weight));
+    }
+
+    // the span is a comment, so that the text parses back to the same rule
+    format!(
+        "{:5}: {}{} = {}  // {}",
+        rule.id, annotations, inputs, outputs, rule.span
+    )
+}
@@ -0,0 +1,321 @@
+use super::parser::Span;
+use super::{
+    first_token, is_negated_pred, is_whole_phrase_var, Atom, AtomKind, Phrase, Rule, StringCache,
+    Term,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LintKind {
+    /// A variable in an output that none of the rule's inputs bind.
+    UnboundVariable,
+    /// A stage that rules need but nothing produces, so they never fire.
+    UnreachableStage,
+    /// A predicate that rules produce but nothing consumes.
+    NeverConsumed,
+    /// A predicate that rules consume but nothing produces.
+    NeverProduced,
+    /// A predicate used with a different number of arguments than its first use.
+    ArityMismatch,
+}
+
+/// A likely mistake in a program, found by `Context::lint`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    /// Where the phrase the lint is about was written.
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+// A phrase naming a predicate, in the initial state if it has no span.
+struct Use<'a> {
+    name: Atom,
+    arity: usize,
+    is_stage: bool,
+    is_output: bool,
+    span: Option<&'a Span>,
+}
+
+pub(crate) fn lint(
+    state: &[Phrase],
+    rules: &[Rule],
+    external: &[&str],
+    string_cache: &StringCache,
+) -> Vec<Lint> {
+    let mut lints = vec![];
+
+    // rules are shuffled as they fire, so visit them in source order
+    let mut rules = rules.iter().collect::<Vec<_>>();
+    rules.sort_by_key(|r| r.id);
+
+    let qui = string_cache.to_existing_atom("qui");
+    let external = external
+        .iter()
+        .filter_map(|name| string_cache.to_existing_atom(name))
+        .collect::<HashSet<_>>();
+
+    let mut uses = vec![];
+    let mut arguments = vec![];
+
+    // a single variable input consumes any phrase, and a single variable
+    // output can produce any atom or list that the rules pass around
+    let mut consumes_any = false;
+    let mut produces_arguments = false;
+
+    for phrase in state.iter() {
+        uses.extend(predicate_use(phrase, true, None, qui));
+        arguments.extend(phrase.iter().skip(1));
+    }
+
+    for rule in rules.iter() {
+        lint_unbound_variables(rule, string_cache, &mut lints);
+
+        for (input, span) in rule.inputs.iter().zip(rule.input_spans.iter()) {
+            consumes_any |= is_whole_phrase_var(input);
+            uses.extend(predicate_use(input, false, Some(span), qui));
+        }
+
+        for (output, span) in rule.outputs.iter().zip(rule.output_spans.iter()) {
+            // a stage label or `$` phrase puts back what its input consumed
+            if rule.input_spans.contains(span) {
+                continue;
+            }
+
+            produces_arguments |= is_whole_phrase_var(output);
+            uses.extend(predicate_use(output, true, Some(span), qui));
+            arguments.extend(output.iter().skip(1));
+        }
+    }
+
+    let mut first_uses: HashMap<Atom, &Use> = HashMap::new();
+    let mut reported = HashSet::new();
+
+    for u in uses.iter() {
+        let first = *first_uses.entry(u.name).or_insert(u);
+
+        if let Some(span) = u.span {
+            if u.arity != first.arity && reported.insert((u.name, u.arity)) {
+                let first_span = match first.span {
+                    Some(span) => format!("at {}", span),
+                    None => "in the initial state".to_string(),
+                };
+
+                lints.push(Lint {
+                    kind: LintKind::ArityMismatch,
+                    span: span.clone(),
+                    message: format!(
+                        "`{}` has {} here, but {} {}",
+                        string_cache.from_atom(u.name),
+                        plural(u.arity, "argument"),
+                        plural(first.arity, "argument"),
+                        first_span
+                    ),
+                });
+            }
+        }
+    }
+
+    let mut produced_names = uses
+        .iter()
+        .filter(|u| u.is_output)
+        .map(|u| u.name)
+        .collect::<HashSet<_>>();
+    let consumed_names = uses
+        .iter()
+        .filter(|u| !u.is_output)
+        .map(|u| u.name)
+        .collect::<HashSet<_>>();
+
+    if produces_arguments {
+        let mut names = vec![];
+        for term in arguments {
+            argument_names(term, &mut names);
+        }
+
+        produced_names.extend(names);
+    }
+
+    let mut reported = HashSet::new();
+
+    for u in uses.iter().filter(|u| !u.is_output) {
+        if produced_names.contains(&u.name)
+            || external.contains(&u.name)
+            || !reported.insert(u.name)
+        {
+            continue;
+        }
+
+        let name = string_cache.from_atom(u.name);
+        let (kind, message) = if u.is_stage {
+            (
+                LintKind::UnreachableStage,
+                format!(
+                    "`{}` is never produced, so rules that need it can't fire",
+                    name
+                ),
+            )
+        } else {
+            (
+                LintKind::NeverProduced,
+                format!("`{}` is consumed but never produced", name),
+            )
+        };
+
+        lints.push(Lint {
+            kind,
+            span: u.span.cloned().unwrap_or_default(),
+            message,
+        });
+    }
+
+    if !consumes_any {
+        for u in uses.iter().filter(|u| u.is_output) {
+            let span = match u.span {
+                Some(span) => span,
+                None => continue,
+            };
+
+            if consumed_names.contains(&u.name)
+                || external.contains(&u.name)
+                || !reported.insert(u.name)
+            {
+                continue;
+            }
+
+            lints.push(Lint {
+                kind: LintKind::NeverConsumed,
+                span: span.clone(),
+                message: format!(
+                    "`{}` is produced but never consumed",
+                    string_cache.from_atom(u.name)
+                ),
+            });
+        }
+    }
+
+    lints.sort_by(|a, b| {
+        (&a.span.file, a.span.line, a.span.column).cmp(&(&b.span.file, b.span.line, b.span.column))
+    });
+    lints
+}
+
+// The predicate a phrase uses, if it starts with an atom that isn't a
+// variable, number, backwards or side predicate, or the quiescence marker.
+fn predicate_use<'a>(
+    phrase: &Phrase,
+    is_output: bool,
+    span: Option<&'a Span>,
+    qui: Option<Atom>,
+) -> Option<Use<'a>> {
+    let token = first_token(phrase)?;
+
+    let is_symbol = match token.string.kind {
+        AtomKind::Symbol(_) => true,
+        AtomKind::Number(_) => false,
+    };
+
+    if !is_symbol
+        || token.is_var
+        || token.is_side
+        || token.backwards_pred.is_some()
+        || Some(token.string) == qui
+    {
+        return None;
+    }
+
+    Some(Use {
+        name: token.string,
+        arity: phrase.len() - 1,
+        is_stage: token.is_stage,
+        is_output,
+        span,
+    })
+}
+
+// The predicates that an argument would name if a whole phrase variable
+// produced it: an atom, or a list starting with an atom.
+fn argument_names(term: &Term, names: &mut Vec<Atom>) {
+    match *term {
+        Term::Atom(ref token) => {
+            if !token.is_var {
+                names.push(token.string);
+            }
+        }
+        Term::List(ref terms) | Term::Cons(ref terms, _) => {
+            for term in terms.iter() {
+                argument_names(term, names);
+            }
+        }
+    }
+}
+
+fn lint_unbound_variables(rule: &Rule, string_cache: &StringCache, lints: &mut Vec<Lint>) {
+    // a negated input only matches when nothing binds its variables
+    let mut bound = vec![];
+    for input in rule.inputs.iter().filter(|p| !is_negated_pred(p)) {
+        for term in input.iter() {
+            collect_vars(term, &mut bound);
+        }
+    }
+
+    let mut reported = vec![];
+
+    for (output, span) in rule.outputs.iter().zip(rule.output_spans.iter()) {
+        let mut vars = vec![];
+        for term in output.iter() {
+            collect_vars(term, &mut vars);
+        }
+
+        for var in vars {
+            if bound.contains(&var) || reported.contains(&var) {
+                continue;
+            }
+
+            reported.push(var);
+            lints.push(Lint {
+                kind: LintKind::UnboundVariable,
+                span: span.clone(),
+                message: format!("`{}` isn't bound by any input", string_cache.from_atom(var)),
+            });
+        }
+    }
+}
+
+fn collect_vars(term: &Term, vars: &mut Vec<Atom>) {
+    match *term {
+        Term::Atom(ref token) => {
+            if token.is_var {
+                vars.push(token.string);
+            }
+        }
+        Term::List(ref terms) => {
+            for term in terms.iter() {
+                collect_vars(term, vars);
+            }
+        }
+        Term::Cons(ref terms, ref tail) => {
+            for term in terms.iter() {
+                collect_vars(term, vars);
+            }
+
+            collect_vars(tail, vars);
+        }
+    }
+}
+
+fn plural(n: usize, word: &str) -> String {
+    if n == 1 {
+        format!("{} {}", n, word)
+    } else {
+        format!("{} {}s", n, word)
+    }
+}
@@ -0,0 +1,181 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// A numeric atom. Arithmetic on two integers stays in integers; anything
+/// involving a float is done in floats. An integer and a float are equal if
+/// they have the same value, so `16` matches `16.0`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Parses text that starts with a digit, or `-` and a digit. Other text,
+    /// including `inf` and `NaN`, is a symbol.
+    pub fn parse(text: &str) -> Option<Number> {
+        let mut chars = text.chars();
+        let first = match chars.next() {
+            Some('-') => chars.next(),
+            first => first,
+        };
+
+        if !first.is_some_and(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        if let Ok(v) = i64::from_str(text) {
+            Some(Number::Int(v))
+        } else {
+            f64::from_str(text).ok().map(Number::Float)
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(v) => v as f64,
+            Number::Float(v) => v,
+        }
+    }
+
+    pub fn add(self, other: Number) -> Option<Number> {
+        self.op(other, i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn sub(self, other: Number) -> Option<Number> {
+        self.op(other, i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn mul(self, other: Number) -> Option<Number> {
+        self.op(other, i64::checked_mul, |a, b| a * b)
+    }
+
+    /// Divides exactly: two integers give an integer only if there is no
+    /// remainder. Fails on division by zero.
+    pub fn div(self, other: Number) -> Option<Number> {
+        if other.to_f64() == 0.0 {
+            return None;
+        }
+
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) if a.checked_rem(b) == Some(0) => {
+                a.checked_div(b).map(Number::Int)
+            }
+            (a, b) => Some(Number::Float(a.to_f64() / b.to_f64())),
+        }
+    }
+
+    /// Divides, rounding towards negative infinity. Fails on division by zero.
+    pub fn floor_div(self, other: Number) -> Option<Number> {
+        if other.to_f64() == 0.0 {
+            return None;
+        }
+
+        self.op(
+            other,
+            |a, b| {
+                a.checked_div(b).map(|q| {
+                    if a % b != 0 && (a < 0) != (b < 0) {
+                        q - 1
+                    } else {
+                        q
+                    }
+                })
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+
+    pub fn min(self, other: Number) -> Option<Number> {
+        Some(if other < self { other } else { self })
+    }
+
+    pub fn max(self, other: Number) -> Option<Number> {
+        Some(if other > self { other } else { self })
+    }
+
+    pub fn abs(self) -> Option<Number> {
+        match self {
+            Number::Int(v) => v.checked_abs().map(Number::Int),
+            Number::Float(v) => Some(Number::Float(v.abs())),
+        }
+    }
+
+    /// The remainder of `self / n` rounded down, which has the sign of `n`.
+    /// Fails if `n` is zero.
+    pub fn mod_neg(self, n: Number) -> Option<Number> {
+        if n.to_f64() == 0.0 {
+            return None;
+        }
+
+        self.op(
+            n,
+            |x, n| {
+                x.checked_rem(n).map(|r| {
+                    if r != 0 && (r < 0) != (n < 0) {
+                        r + n
+                    } else {
+                        r
+                    }
+                })
+            },
+            |x, n| x - n * (x / n).floor(),
+        )
+    }
+
+    // Applies an integer operation if both numbers are integers, otherwise a
+    // float operation. Fails if the integer operation overflows.
+    fn op<I, F>(self, other: Number, int_op: I, float_op: F) -> Option<Number>
+    where
+        I: Fn(i64, i64) -> Option<i64>,
+        F: Fn(f64, f64) -> f64,
+    {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => int_op(a, b).map(Number::Int),
+            (a, b) => Some(Number::Float(float_op(a.to_f64(), b.to_f64()))),
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        match (*self, *other) {
+            (Number::Int(a), Number::Int(b)) => a == b,
+            // compare NaN by its bits, so that every number is equal to itself
+            (a, b) => a.to_f64() == b.to_f64() || a.to_f64().to_bits() == b.to_f64().to_bits(),
+        }
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (*self, *other) {
+            (Number::Int(a), Number::Int(b)) => a.partial_cmp(&b),
+            (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+        }
+    }
+}
+
+impl Hash for Number {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // hash the value `eq` compares, with `-0.0` as `0.0`, so that equal
+        // numbers hash the same even where an integer rounds to a float
+        let v = self.to_f64();
+        let v = if v == 0.0 { 0.0 } else { v };
+        v.to_bits().hash(state)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Number::Int(v) => write!(f, "{}", v),
+            // Debug keeps the `.0` of whole floats, so that they parse back as floats
+            Number::Float(v) => write!(f, "{:?}", v),
+        }
+    }
+}
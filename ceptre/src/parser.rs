@@ -0,0 +1,1278 @@
+use super::number::Number;
+use super::types::{self, ArgType, TypeDecl, Types};
+use super::{Phrase, Rule, StringCache, Term, Token};
+
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::vec::Vec;
+
+// Only `update` adds the quiescence marker to the state, and it expects to
+// find just the one it added.
+pub(crate) const QUIESCENCE_IN_STATE: &str = "the quiescence marker can't be added to the state";
+
+/// Where a rule or phrase was written in the program text, shown by
+/// `rule_to_string` and `firing_to_string` as a trailing comment.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Span {
+    /// The name the text was loaded with, if any.
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+    /// The `#stage:` label the statement was attached to, if any.
+    pub stage: Option<Rc<str>>,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+
+        write!(f, "{}:{}", self.line, self.column)?;
+
+        if let Some(ref stage) = self.stage {
+            write!(f, " in {}", stage)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// The name the text was loaded with, if any.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+
+        write!(
+            f,
+            "{}:{}: {}: `{}`",
+            self.line, self.column, self.reason, self.text
+        )
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl ParseError {
+    // An error in a phrase that wasn't parsed from text, such as one made with
+    // a `PhraseBuilder`, which is reported at line 1, column 1.
+    pub(crate) fn unparsed(text: String, reason: &str) -> ParseError {
+        ParseError {
+            file: None,
+            line: 1,
+            column: 1,
+            text,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Lexeme {
+    Atom(String),
+    // a quoted path after `#include`
+    Str(String),
+    Open,
+    Close,
+    Dot,
+    Equals,
+    Colon,
+    // end of a line containing at least one lexeme
+    Newline,
+    // end of a line containing only whitespace
+    Blank,
+    End,
+}
+
+#[derive(Clone, Debug)]
+struct Spanned {
+    lexeme: Lexeme,
+    line: usize,
+    column: usize,
+}
+
+impl Spanned {
+    fn text(&self) -> String {
+        match self.lexeme {
+            Lexeme::Atom(ref s) => s.clone(),
+            Lexeme::Str(ref s) => format!("\"{}\"", s),
+            Lexeme::Open => "(".to_string(),
+            Lexeme::Close => ")".to_string(),
+            Lexeme::Dot => ".".to_string(),
+            Lexeme::Equals => "=".to_string(),
+            Lexeme::Colon => ":".to_string(),
+            Lexeme::Newline | Lexeme::Blank => "\\n".to_string(),
+            Lexeme::End => "".to_string(),
+        }
+    }
+
+    fn error(&self, reason: &str) -> ParseError {
+        ParseError {
+            file: None,
+            line: self.line,
+            column: self.column,
+            text: self.text(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn lex(text: &str) -> Result<Vec<Spanned>, ParseError> {
+    let mut result = vec![];
+
+    let mut line_count = 0;
+
+    // the start of the block comment that the current position is inside, if any
+    let mut block_comment = None;
+
+    for (i, line) in text.split('\n').enumerate() {
+        let number = i + 1;
+        line_count = number;
+
+        let chars = line.trim_end_matches('\r').chars().collect::<Vec<_>>();
+        let line_start = result.len();
+        let mut has_comment = block_comment.is_some();
+
+        let mut c_i = 0;
+        while c_i < chars.len() {
+            let c = chars[c_i];
+            let column = c_i + 1;
+
+            if block_comment.is_some() {
+                if c == '*' && chars.get(c_i + 1) == Some(&'/') {
+                    block_comment = None;
+                    c_i += 2;
+                } else {
+                    c_i += 1;
+                }
+
+                continue;
+            }
+
+            if c.is_whitespace() {
+                c_i += 1;
+                continue;
+            }
+
+            if c == '/' && chars.get(c_i + 1) == Some(&'/') {
+                has_comment = true;
+                break;
+            }
+
+            if c == '/' && chars.get(c_i + 1) == Some(&'*') {
+                has_comment = true;
+                block_comment = Some((number, column));
+                c_i += 2;
+                continue;
+            }
+
+            if c == '"' {
+                let end = match chars[c_i + 1..].iter().position(|&c| c == '"') {
+                    Some(len) => c_i + 1 + len,
+                    None => {
+                        return Err(ParseError {
+                            file: None,
+                            line: number,
+                            column,
+                            text: chars[c_i..].iter().collect(),
+                            reason: "unterminated string".to_string(),
+                        })
+                    }
+                };
+
+                result.push(Spanned {
+                    lexeme: Lexeme::Str(chars[c_i + 1..end].iter().collect()),
+                    line: number,
+                    column,
+                });
+
+                c_i = end + 1;
+                continue;
+            }
+
+            if c == '(' || c == ')' {
+                result.push(Spanned {
+                    lexeme: if c == '(' {
+                        Lexeme::Open
+                    } else {
+                        Lexeme::Close
+                    },
+                    line: number,
+                    column,
+                });
+
+                c_i += 1;
+                continue;
+            }
+
+            // a comment ends an atom, even without a space before it
+            let starts_comment =
+                |i: usize| chars[i] == '/' && matches!(chars.get(i + 1), Some('/') | Some('*'));
+
+            let start = c_i;
+            while c_i < chars.len()
+                && !chars[c_i].is_whitespace()
+                && chars[c_i] != '('
+                && chars[c_i] != ')'
+                && !starts_comment(c_i)
+            {
+                c_i += 1;
+            }
+
+            let atom = chars[start..c_i].iter().collect::<String>();
+
+            match atom.as_str() {
+                "." => result.push(Spanned {
+                    lexeme: Lexeme::Dot,
+                    line: number,
+                    column,
+                }),
+                "=" => result.push(Spanned {
+                    lexeme: Lexeme::Equals,
+                    line: number,
+                    column,
+                }),
+                ":" => result.push(Spanned {
+                    lexeme: Lexeme::Colon,
+                    line: number,
+                    column,
+                }),
+                _ if atom.ends_with(':') => {
+                    result.push(Spanned {
+                        lexeme: Lexeme::Atom(atom[..atom.len() - 1].to_string()),
+                        line: number,
+                        column,
+                    });
+                    result.push(Spanned {
+                        lexeme: Lexeme::Colon,
+                        line: number,
+                        column: c_i,
+                    });
+                }
+                _ => result.push(Spanned {
+                    lexeme: Lexeme::Atom(atom),
+                    line: number,
+                    column,
+                }),
+            }
+        }
+
+        // comments are stripped before labels are attached: lines containing
+        // only comments are skipped entirely, so that they don't end the
+        // current label block.
+        if result.len() > line_start {
+            result.push(Spanned {
+                lexeme: Lexeme::Newline,
+                line: number,
+                column: chars.len() + 1,
+            });
+        } else if !has_comment {
+            result.push(Spanned {
+                lexeme: Lexeme::Blank,
+                line: number,
+                column: 1,
+            });
+        }
+    }
+
+    if let Some((line, column)) = block_comment {
+        return Err(ParseError {
+            file: None,
+            line,
+            column,
+            text: "/*".to_string(),
+            reason: "unterminated block comment".to_string(),
+        });
+    }
+
+    result.push(Spanned {
+        lexeme: Lexeme::End,
+        line: line_count,
+        column: 1,
+    });
+
+    Ok(result)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum TermAst {
+    Atom(String),
+    // a number from a `PhraseBuilder`, which isn't written as text
+    Number(Number),
+    List(Vec<TermAst>),
+    Cons(Vec<TermAst>, Box<TermAst>),
+}
+
+#[derive(Clone, Debug)]
+struct PhraseAst {
+    terms: Vec<TermAst>,
+    line: usize,
+    column: usize,
+}
+
+impl PhraseAst {
+    fn text(&self) -> String {
+        fn term_text(term: &TermAst) -> String {
+            match *term {
+                TermAst::Atom(ref s) => s.clone(),
+                TermAst::Number(n) => n.to_string(),
+                TermAst::List(ref terms) => {
+                    format!(
+                        "({})",
+                        terms.iter().map(term_text).collect::<Vec<_>>().join(" ")
+                    )
+                }
+                TermAst::Cons(ref terms, ref tail) => format!(
+                    "({} | {})",
+                    terms.iter().map(term_text).collect::<Vec<_>>().join(" "),
+                    term_text(tail)
+                ),
+            }
+        }
+
+        self.terms
+            .iter()
+            .map(term_text)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn is_quiescence(&self) -> bool {
+        match self.terms.as_slice() {
+            [TermAst::Atom(ref s)] => s == "qui",
+            [TermAst::List(ref terms)] => terms.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn to_phrase(&self, string_cache: &mut StringCache) -> Phrase {
+        terms_to_phrase(&self.terms, string_cache)
+    }
+
+    fn to_checked_phrase(
+        &self,
+        types: &Types,
+        string_cache: &mut StringCache,
+    ) -> Result<Phrase, ParseError> {
+        let phrase = self.to_phrase(string_cache);
+
+        types::check_phrase(&phrase, types, string_cache).map_err(|e| ParseError {
+            file: None,
+            line: self.line,
+            column: self.column,
+            text: e.text,
+            reason: e.reason,
+        })?;
+
+        Ok(phrase)
+    }
+}
+
+/// The phrase made of `terms`, which must not be empty. A phrase of only
+/// `()` is the quiescence marker.
+pub(crate) fn terms_to_phrase(terms: &[TermAst], string_cache: &mut StringCache) -> Phrase {
+    match terms {
+        [TermAst::List(ref list)] if list.is_empty() => {
+            vec![Term::Atom(Token::new("qui", string_cache))]
+        }
+        _ => terms.iter().map(|t| to_term(t, string_cache)).collect(),
+    }
+}
+
+fn to_term(term: &TermAst, string_cache: &mut StringCache) -> Term {
+    match *term {
+        TermAst::Atom(ref s) => Term::Atom(Token::new(s, string_cache)),
+        TermAst::Number(n) => Term::number(n),
+        TermAst::List(ref terms) => {
+            Term::List(terms.iter().map(|t| to_term(t, string_cache)).collect())
+        }
+        TermAst::Cons(ref terms, ref tail) => Term::Cons(
+            terms.iter().map(|t| to_term(t, string_cache)).collect(),
+            Box::new(to_term(tail, string_cache)),
+        ),
+    }
+}
+
+enum Statement {
+    Include {
+        path: String,
+        // the `#include`, for errors
+        start: Spanned,
+    },
+    Label(PhraseAst),
+    // `type name arg:type...`
+    Type {
+        name: String,
+        args: Vec<(String, ArgType)>,
+        // the `type`, for errors
+        start: Spanned,
+    },
+    State(Vec<PhraseAst>),
+    Rule {
+        line: usize,
+        column: usize,
+        annotations: Annotations,
+        inputs: Vec<PhraseAst>,
+        dollars: Vec<PhraseAst>,
+        outputs: Vec<PhraseAst>,
+    },
+}
+
+// `@name value` pairs preceding a rule, on the same line or the lines before it.
+#[derive(Default)]
+struct Annotations {
+    priority: Option<i32>,
+    weight: Option<u32>,
+    // the first annotation, for errors on statements that aren't rules
+    start: Option<Spanned>,
+}
+
+struct Parser {
+    lexemes: Vec<Spanned>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Spanned {
+        &self.lexemes[self.pos]
+    }
+
+    fn peek_next(&self) -> &Spanned {
+        &self.lexemes[(self.pos + 1).min(self.lexemes.len() - 1)]
+    }
+
+    fn advance(&mut self) -> Spanned {
+        let result = self.lexemes[self.pos].clone();
+
+        if self.pos < self.lexemes.len() - 1 {
+            self.pos += 1;
+        }
+
+        result
+    }
+
+    fn is_end_of_statement(&self) -> bool {
+        match self.peek().lexeme {
+            Lexeme::Newline => match self.peek_next().lexeme {
+                // a line starting with `.` or `=` continues the previous statement
+                Lexeme::Dot | Lexeme::Equals => false,
+                _ => true,
+            },
+            Lexeme::Blank | Lexeme::End => true,
+            _ => false,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().lexeme == Lexeme::Newline {
+            self.advance();
+        }
+    }
+
+    fn parse_annotations(&mut self) -> Result<Annotations, ParseError> {
+        let mut annotations = Annotations::default();
+
+        loop {
+            let name = match self.peek().lexeme {
+                Lexeme::Atom(ref s) if s.starts_with('@') => s[1..].to_string(),
+                _ => break,
+            };
+
+            let start = self.advance();
+            annotations.start.get_or_insert(start.clone());
+
+            let value = self.advance();
+            let text = match value.lexeme {
+                Lexeme::Atom(ref s) => s.clone(),
+                _ => return Err(start.error(&format!("expected a value after `@{}`", name))),
+            };
+
+            match name.as_str() {
+                "priority" => {
+                    let priority = text
+                        .parse()
+                        .map_err(|_| value.error("expected an integer priority"))?;
+                    annotations.priority = Some(priority);
+                }
+                "weight" => {
+                    let weight = text
+                        .parse()
+                        .ok()
+                        .filter(|&weight| weight > 0)
+                        .ok_or_else(|| value.error("expected a positive integer weight"))?;
+                    annotations.weight = Some(weight);
+                }
+                _ => return Err(start.error("unknown annotation")),
+            }
+
+            self.skip_newlines();
+        }
+
+        Ok(annotations)
+    }
+
+    // Returns None at a blank line or the end of the text, otherwise the next statement.
+    fn parse_statement(&mut self) -> Result<Option<Statement>, ParseError> {
+        match self.peek().lexeme {
+            Lexeme::Blank | Lexeme::End => return Ok(None),
+            _ => (),
+        }
+
+        let annotations = self.parse_annotations()?;
+
+        if let Some(ref start) = annotations.start {
+            match self.peek().lexeme {
+                Lexeme::Blank | Lexeme::End => {
+                    return Err(start.error("expected a rule after annotation"))
+                }
+                _ => (),
+            }
+        }
+
+        match self.peek().lexeme {
+            Lexeme::Atom(ref s) if s == "#include" => {
+                if let Some(start) = annotations.start {
+                    return Err(start.error("annotations are only allowed on rules"));
+                }
+
+                return self.parse_include().map(Some);
+            }
+            Lexeme::Atom(ref s) if s == "type" => {
+                if let Some(start) = annotations.start {
+                    return Err(start.error("annotations are only allowed on rules"));
+                }
+
+                return self.parse_type().map(Some);
+            }
+            _ => (),
+        }
+
+        let line = self.peek().line;
+        let column = self.peek().column;
+        let mut inputs = vec![];
+        let mut dollars = vec![];
+        let mut outputs = vec![];
+        let mut is_rule = false;
+        let mut dollar_start = None;
+
+        loop {
+            let start = self.peek().clone();
+            let (phrase, is_dollar) = self.parse_phrase()?;
+
+            if phrase.terms.is_empty() {
+                if is_rule && outputs.is_empty() && self.is_end_of_statement() {
+                    // a rule without outputs
+                    break;
+                }
+
+                return Err(match start.lexeme {
+                    Lexeme::Str(_)
+                    | Lexeme::Dot
+                    | Lexeme::Equals
+                    | Lexeme::Colon
+                    | Lexeme::Close => start.error(&format!("unexpected `{}`", start.text())),
+                    _ => start.error("empty phrase"),
+                });
+            }
+
+            if is_dollar && is_rule {
+                return Err(start.error("`$` is only allowed on rule inputs"));
+            }
+
+            match (is_rule, is_dollar) {
+                (false, false) => inputs.push(phrase),
+                (false, true) => {
+                    dollar_start.get_or_insert(start.clone());
+                    dollars.push(phrase);
+                }
+                (true, _) => outputs.push(phrase),
+            }
+
+            if self.peek().lexeme == Lexeme::Newline && !self.is_end_of_statement() {
+                self.advance();
+            }
+
+            let separator = self.peek().clone();
+            match separator.lexeme {
+                Lexeme::Dot => {
+                    self.advance();
+                    self.skip_newlines();
+                }
+                Lexeme::Equals if !is_rule => {
+                    self.advance();
+                    is_rule = true;
+                }
+                Lexeme::Colon if !is_rule && inputs.len() + dollars.len() == 1 => {
+                    self.advance();
+
+                    if !self.is_end_of_statement() {
+                        return Err(self.peek().error("expected end of line after label"));
+                    }
+
+                    if !dollars.is_empty() || !starts_with_stage(&inputs[0]) {
+                        return Err(start.error("expected a stage before `:`"));
+                    }
+
+                    if let Some(start) = annotations.start {
+                        return Err(start.error("annotations are only allowed on rules"));
+                    }
+
+                    self.skip_newlines();
+                    return Ok(Some(Statement::Label(inputs.remove(0))));
+                }
+                Lexeme::Newline | Lexeme::Blank | Lexeme::End => break,
+                _ => return Err(separator.error(&format!("unexpected `{}`", separator.text()))),
+            }
+        }
+
+        self.skip_newlines();
+
+        if is_rule {
+            Ok(Some(Statement::Rule {
+                line,
+                column,
+                annotations,
+                inputs,
+                dollars,
+                outputs,
+            }))
+        } else if let Some(start) = dollar_start {
+            Err(start.error("`$` is only allowed on rule inputs"))
+        } else if let Some(start) = annotations.start {
+            Err(start.error("annotations are only allowed on rules"))
+        } else {
+            Ok(Some(Statement::State(inputs)))
+        }
+    }
+
+    // `#include "path"`, which must be on a line of its own.
+    fn parse_include(&mut self) -> Result<Statement, ParseError> {
+        let start = self.advance();
+
+        let path = match self.peek().lexeme {
+            Lexeme::Str(ref path) => path.clone(),
+            _ => return Err(start.error("expected a quoted path after `#include`")),
+        };
+
+        self.advance();
+
+        if !self.is_end_of_statement() {
+            return Err(self.peek().error("expected end of line after include path"));
+        }
+
+        self.skip_newlines();
+
+        Ok(Statement::Include { path, start })
+    }
+
+    // `type name arg:type...`, which must be on a line of its own.
+    fn parse_type(&mut self) -> Result<Statement, ParseError> {
+        let start = self.advance();
+
+        let name = match self.peek().lexeme {
+            Lexeme::Atom(ref name) => name.clone(),
+            _ => return Err(start.error("expected a predicate after `type`")),
+        };
+
+        self.advance();
+
+        let mut args = vec![];
+
+        while let Lexeme::Atom(ref text) = self.peek().lexeme {
+            let arg = match text.find(':') {
+                Some(i) if i > 0 => match ArgType::parse(&text[i + 1..]) {
+                    Some(arg_type) => (text[..i].to_string(), arg_type),
+                    None => {
+                        return Err(self.peek().error(
+                            "unknown type, expected `int`, `float`, `atom`, `list` or `any`",
+                        ))
+                    }
+                },
+                _ => return Err(self.peek().error("expected `name:type`")),
+            };
+
+            args.push(arg);
+            self.advance();
+        }
+
+        if !self.is_end_of_statement() {
+            let unexpected = self.peek();
+            return Err(unexpected.error(&format!("unexpected `{}`", unexpected.text())));
+        }
+
+        self.skip_newlines();
+
+        Ok(Statement::Type { name, args, start })
+    }
+
+    // Parses a sequence of terms up to the next separator, returning
+    // the terms and whether the phrase was prefixed with `$`.
+    fn parse_phrase(&mut self) -> Result<(PhraseAst, bool), ParseError> {
+        let line = self.peek().line;
+        let column = self.peek().column;
+        let mut terms = vec![];
+        let mut is_dollar = false;
+
+        while let Lexeme::Atom(_) | Lexeme::Open = self.peek().lexeme {
+            let mut term = self.parse_term()?;
+
+            if terms.is_empty() {
+                if let TermAst::Atom(ref mut s) = term {
+                    if s.starts_with('$') {
+                        s.remove(0);
+                        is_dollar = true;
+
+                        if s.is_empty() {
+                            return Err(
+                                self.lexemes[self.pos - 1].error("expected an atom after `$`")
+                            );
+                        }
+
+                        if s == "!" {
+                            return Err(
+                                self.lexemes[self.pos - 1].error("expected an atom after `$!`")
+                            );
+                        }
+                    }
+                }
+            }
+
+            terms.push(term);
+        }
+
+        Ok((
+            PhraseAst {
+                terms,
+                line,
+                column,
+            },
+            is_dollar,
+        ))
+    }
+
+    fn parse_term(&mut self) -> Result<TermAst, ParseError> {
+        let lexeme = self.advance();
+
+        match lexeme.lexeme {
+            Lexeme::Atom(ref s) if s == "!" => Err(lexeme.error("expected an atom after `!`")),
+            Lexeme::Atom(s) => Ok(TermAst::Atom(s)),
+            Lexeme::Open => {
+                let mut terms = vec![];
+
+                loop {
+                    self.skip_newlines();
+
+                    match self.peek().lexeme {
+                        Lexeme::Close => {
+                            self.advance();
+                            break;
+                        }
+                        Lexeme::Atom(ref s) if s == "|" => return self.parse_cons(terms),
+                        Lexeme::Atom(_) | Lexeme::Open => terms.push(self.parse_term()?),
+                        _ => return Err(lexeme.error("unclosed `(`")),
+                    }
+                }
+
+                // `()` is the empty list, or the quiescence marker on its own
+                Ok(TermAst::List(terms))
+            }
+            _ => Err(lexeme.error(&format!("unexpected `{}`", lexeme.text()))),
+        }
+    }
+
+    // The tail of `(A B | T)`, after `A B`.
+    fn parse_cons(&mut self, terms: Vec<TermAst>) -> Result<TermAst, ParseError> {
+        let bar = self.advance();
+
+        if terms.is_empty() {
+            return Err(bar.error("expected a term before `|`"));
+        }
+
+        self.skip_newlines();
+
+        let tail = match self.peek().lexeme {
+            Lexeme::Atom(ref s) if s == "|" => return Err(bar.error("expected a term after `|`")),
+            Lexeme::Atom(_) | Lexeme::Open => self.parse_term()?,
+            _ => return Err(bar.error("expected a term after `|`")),
+        };
+
+        self.skip_newlines();
+
+        match self.peek().lexeme {
+            Lexeme::Close => {
+                self.advance();
+                Ok(TermAst::Cons(terms, Box::new(tail)))
+            }
+            _ => Err(bar.error("expected `)` after the tail of a list")),
+        }
+    }
+}
+
+fn starts_with_stage(phrase: &PhraseAst) -> bool {
+    phrase.terms.first().is_some_and(|term| match *term {
+        TermAst::Atom(ref s) => s.starts_with('#'),
+        _ => false,
+    })
+}
+
+// Reads the text of the file at a path.
+type ReadFile<'a> = dyn FnMut(&str) -> io::Result<String> + 'a;
+
+/// A program's initial state, rules and `type` declarations.
+pub struct Parsed {
+    pub state: Vec<Phrase>,
+    /// Where each phrase of `state` was written.
+    pub state_spans: Vec<Span>,
+    pub rules: Vec<Rule>,
+    pub types: Types,
+}
+
+// The initial state and rules parsed so far from the files of a program.
+struct Program<'a> {
+    state: Vec<Phrase>,
+    // where each phrase of the initial state was written, for type errors
+    state_spans: Vec<Span>,
+    rules: Vec<Rule>,
+    types: Types,
+    // the id of the next statement, counting on from the previous file
+    id: i32,
+    string_cache: &'a mut StringCache,
+    // reads an included file, or None if the text can't include files
+    read: Option<&'a mut ReadFile<'a>>,
+    // the files being parsed, with the innermost last
+    stack: Vec<Rc<str>>,
+    included: HashSet<Rc<str>>,
+}
+
+impl<'a> Program<'a> {
+    fn new(string_cache: &'a mut StringCache) -> Program<'a> {
+        Program {
+            state: vec![],
+            state_spans: vec![],
+            rules: vec![],
+            types: Types::new(),
+            id: 0,
+            string_cache,
+            read: None,
+            stack: vec![],
+            included: HashSet::new(),
+        }
+    }
+
+    // Parses the file at `path`, resolved relative to the including file. A
+    // file that has already been included is skipped.
+    fn include(&mut self, path: &str, start: Option<&Spanned>) -> Result<(), ParseError> {
+        let error = |reason: String| match start {
+            Some(start) => start.error(&reason),
+            None => ParseError {
+                file: Some(path.to_string()),
+                line: 1,
+                column: 1,
+                text: String::new(),
+                reason,
+            },
+        };
+
+        let name: Rc<str> = match self.stack.last() {
+            Some(including) => Rc::from(resolve(including, path)),
+            None => Rc::from(normalize(Path::new(path))),
+        };
+
+        if self.stack.contains(&name) {
+            let cycle = self
+                .stack
+                .iter()
+                .skip_while(|&f| *f != name)
+                .chain(Some(&name))
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>();
+
+            return Err(error(format!("include cycle: {}", cycle.join(" -> "))));
+        }
+
+        if !self.included.insert(name.clone()) {
+            return Ok(());
+        }
+
+        let text = match self.read {
+            Some(ref mut read) => {
+                read(&name).map_err(|e| error(format!("can't read `{}`: {}", name, e)))?
+            }
+            None => {
+                return Err(error(
+                    "`#include` needs a program loaded with `Context::from_files`".to_string(),
+                ))
+            }
+        };
+
+        self.stack.push(name.clone());
+        self.parse(&text, Some(name.clone())).map_err(|mut e| {
+            e.file.get_or_insert_with(|| name.to_string());
+            e
+        })?;
+        self.stack.pop();
+
+        Ok(())
+    }
+
+    fn parse(&mut self, text: &str, file: Option<Rc<str>>) -> Result<(), ParseError> {
+        parse_program(text, &file, self)
+    }
+
+    // Checks every phrase against the declarations, which may come after
+    // the phrase or in another file.
+    fn finish(self) -> Result<Parsed, ParseError> {
+        let rule_phrases = self.rules.iter().flat_map(|r| {
+            r.inputs
+                .iter()
+                .zip(r.input_spans.iter())
+                .chain(r.outputs.iter().zip(r.output_spans.iter()))
+        });
+
+        for (phrase, span) in self
+            .state
+            .iter()
+            .zip(self.state_spans.iter())
+            .chain(rule_phrases)
+        {
+            types::check_phrase(phrase, &self.types, self.string_cache).map_err(|e| {
+                ParseError {
+                    file: span.file.as_ref().map(|f| f.to_string()),
+                    line: span.line,
+                    column: span.column,
+                    text: e.text,
+                    reason: e.reason,
+                }
+            })?;
+        }
+
+        Ok(Parsed {
+            state: self.state,
+            state_spans: self.state_spans,
+            rules: self.rules,
+            types: self.types,
+        })
+    }
+}
+
+// The path of `path` included from the file `including`.
+fn resolve(including: &str, path: &str) -> String {
+    match Path::new(including).parent() {
+        Some(dir) => normalize(&dir.join(path)),
+        None => normalize(Path::new(path)),
+    }
+}
+
+// Removes `.` and resolves `..` where possible without reading the file
+// system, so that each file has one name for detecting cycles.
+fn normalize(path: &Path) -> String {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => result.push(".."),
+            },
+            _ => result.push(component.as_os_str()),
+        }
+    }
+
+    result.to_string_lossy().into_owned()
+}
+
+/// Parses program text into its initial state and rules. `file` is the name
+/// given to the text in spans and errors. The text can't use `#include`.
+pub fn parse(
+    text: &str,
+    file: Option<&str>,
+    string_cache: &mut StringCache,
+) -> Result<Parsed, ParseError> {
+    let file = file.map(Rc::from);
+    let mut program = Program::new(string_cache);
+
+    program.parse(text, file.clone()).map_err(|mut e| {
+        e.file = file.as_ref().map(|f| f.to_string());
+        e
+    })?;
+
+    program.finish()
+}
+
+/// Parses the files at `paths` and the files they include, in order, into a
+/// single program. Each file is read with `read`.
+pub fn parse_files<P>(
+    paths: &[P],
+    read: &mut ReadFile,
+    string_cache: &mut StringCache,
+) -> Result<Parsed, ParseError>
+where
+    P: AsRef<Path>,
+{
+    let mut program = Program::new(string_cache);
+    program.read = Some(read);
+
+    for path in paths.iter() {
+        program.include(&path.as_ref().to_string_lossy(), None)?;
+    }
+
+    program.finish()
+}
+
+fn parse_program(
+    text: &str,
+    file: &Option<Rc<str>>,
+    program: &mut Program,
+) -> Result<(), ParseError> {
+    let mut parser = Parser {
+        lexemes: lex(text)?,
+        pos: 0,
+    };
+
+    // the current label, and its text for spans
+    let mut attach: Option<(PhraseAst, Rc<str>)> = None;
+
+    loop {
+        match parser.peek().lexeme {
+            Lexeme::End => break,
+            Lexeme::Blank => {
+                parser.advance();
+                attach = None;
+                continue;
+            }
+            _ => (),
+        }
+
+        let statement = match parser.parse_statement()? {
+            Some(statement) => statement,
+            None => continue,
+        };
+
+        let span = |line, column| Span {
+            file: file.clone(),
+            line,
+            column,
+            stage: attach.as_ref().map(|label| label.1.clone()),
+        };
+
+        match statement {
+            Statement::Include { path, start } => {
+                if attach.is_some() {
+                    return Err(start.error("`#include` isn't allowed in a `#stage:` block"));
+                }
+
+                program.include(&path, Some(&start))?;
+            }
+            Statement::Type { name, args, start } => {
+                if attach.is_some() {
+                    return Err(start.error("`type` isn't allowed in a `#stage:` block"));
+                }
+
+                let token = Token::new(&name, program.string_cache);
+                if token.is_var
+                    || token.is_negated
+                    || token.backwards_pred.is_some()
+                    || token.string.is_number()
+                {
+                    return Err(start.error("expected a predicate after `type`"));
+                }
+
+                let decl = TypeDecl {
+                    name,
+                    args,
+                    span: span(start.line, start.column),
+                };
+
+                if let Some(existing) = program.types.get(&token.string) {
+                    if existing.args != decl.args {
+                        return Err(start.error(&format!(
+                            "`{}` is already declared at {}",
+                            decl.name, existing.span
+                        )));
+                    }
+                }
+
+                program.types.insert(token.string, decl);
+            }
+            Statement::Label(label) => {
+                let stage = Rc::from(label.text());
+                attach = Some((label, stage));
+            }
+            Statement::State(phrases) => {
+                // mirror the rule form below, where the label is both consumed and produced
+                if let Some((ref label, _)) = attach {
+                    program.state.push(label.to_phrase(program.string_cache));
+                    program.state_spans.push(span(label.line, label.column));
+                }
+
+                for p in phrases.iter() {
+                    program.state.push(p.to_phrase(program.string_cache));
+                    program.state_spans.push(span(p.line, p.column));
+                }
+
+                if let Some((ref label, _)) = attach {
+                    program.state.push(label.to_phrase(program.string_cache));
+                    program.state_spans.push(span(label.line, label.column));
+                }
+
+                program.id += 1;
+            }
+            Statement::Rule {
+                line,
+                column,
+                annotations,
+                inputs,
+                dollars,
+                outputs,
+            } => {
+                let mut rule_inputs = vec![];
+                let mut rule_outputs = vec![];
+                let mut input_spans = vec![];
+                let mut output_spans = vec![];
+
+                if let Some((ref label, _)) = attach {
+                    rule_inputs.push(label.to_phrase(program.string_cache));
+                    input_spans.push(span(label.line, label.column));
+                }
+
+                for p in inputs.iter() {
+                    rule_inputs.push(p.to_phrase(program.string_cache));
+                    input_spans.push(span(p.line, p.column));
+                }
+
+                for p in outputs.iter() {
+                    rule_outputs.push(p.to_phrase(program.string_cache));
+                    output_spans.push(span(p.line, p.column));
+                }
+
+                if let Some((ref label, _)) = attach {
+                    // discard the current label on quiescence
+                    if !inputs
+                        .iter()
+                        .chain(dollars.iter())
+                        .chain(outputs.iter())
+                        .any(|p| p.is_quiescence())
+                    {
+                        rule_outputs.push(label.to_phrase(program.string_cache));
+                        output_spans.push(span(label.line, label.column));
+                    }
+                }
+
+                for p in dollars.iter() {
+                    rule_inputs.push(p.to_phrase(program.string_cache));
+                    rule_outputs.push(p.to_phrase(program.string_cache));
+                    input_spans.push(span(p.line, p.column));
+                    output_spans.push(span(p.line, p.column));
+                }
+
+                let mut rule = Rule::new_with_id(program.id, rule_inputs, rule_outputs);
+                rule.span = span(line, column);
+                rule.input_spans = input_spans;
+                rule.output_spans = output_spans;
+                rule.priority = annotations.priority.unwrap_or(0);
+                rule.weight = annotations.weight.unwrap_or(1);
+
+                program.rules.push(rule);
+                program.id += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a pattern: phrases separated by `.`, as on the input side of a rule.
+/// Each phrase is checked against `types`.
+pub fn parse_pattern(
+    text: &str,
+    types: &Types,
+    string_cache: &mut StringCache,
+) -> Result<Vec<Phrase>, ParseError> {
+    let mut parser = Parser {
+        lexemes: lex(text)?,
+        pos: 0,
+    };
+
+    parser.skip_newlines();
+
+    let start = parser.peek().clone();
+
+    let phrases = match parser.parse_statement()? {
+        Some(Statement::State(phrases)) => phrases,
+        Some(_) => return Err(start.error("expected a pattern")),
+        None => return Err(start.error("empty phrase")),
+    };
+
+    match parser.peek().lexeme {
+        Lexeme::Blank | Lexeme::End => (),
+        _ => {
+            let unexpected = parser.peek();
+            return Err(unexpected.error(&format!("unexpected `{}`", unexpected.text())));
+        }
+    }
+
+    phrases
+        .iter()
+        .map(|p| p.to_checked_phrase(types, string_cache))
+        .collect()
+}
+
+/// Parses a single phrase, checked against `types`.
+pub fn parse_phrase(
+    text: &str,
+    types: &Types,
+    string_cache: &mut StringCache,
+) -> Result<Phrase, ParseError> {
+    let mut parser = Parser {
+        lexemes: lex(text)?,
+        pos: 0,
+    };
+
+    parser.skip_newlines();
+
+    let start = parser.peek().clone();
+    let (phrase, is_dollar) = parser.parse_phrase()?;
+
+    if is_dollar {
+        return Err(start.error("`$` is only allowed on rule inputs"));
+    }
+
+    parser.skip_newlines();
+
+    match parser.peek().lexeme {
+        Lexeme::Blank | Lexeme::End => (),
+        _ => {
+            let unexpected = parser.peek();
+            return Err(unexpected.error(&format!("unexpected `{}`", unexpected.text())));
+        }
+    }
+
+    if phrase.terms.is_empty() {
+        return Err(start.error("empty phrase"));
+    }
+
+    if phrase.is_quiescence() {
+        return Err(start.error(QUIESCENCE_IN_STATE));
+    }
+
+    phrase.to_checked_phrase(types, string_cache)
+}
@@ -0,0 +1,160 @@
+use super::{term_to_string, Atom, Bindings, ParseError, StringCache, Term};
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+
+/// A value built from the bindings of a pattern, returned by `Context::query`.
+///
+/// With the `derive` feature, `#[derive(FromPhrase)]` on a struct matches a
+/// phrase of the struct's name in kebab case followed by a variable for each
+/// field, so `struct Note { instrument: i32, pos: i32, desc: String }` reads
+/// `note INSTRUMENT POS DESC`. `#[ceptre(name = "note-tmp")]` on the struct
+/// changes the leading atom.
+pub trait FromPhrase: Sized {
+    /// The pattern passed to `Context::find`.
+    fn pattern() -> String;
+
+    fn from_bindings(
+        bindings: &Bindings,
+        string_cache: &StringCache,
+    ) -> Result<Self, FromPhraseError>;
+}
+
+/// A value that a single variable can be bound to.
+pub trait FromTerm: Sized {
+    /// Converts the value of a variable, or returns the reason it can't be.
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<Self, &'static str>;
+}
+
+/// A variable whose value couldn't be converted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FromPhraseError {
+    pub var: String,
+    /// The value of the variable, or nothing if it wasn't bound.
+    pub text: String,
+    pub reason: String,
+}
+
+impl fmt::Display for FromPhraseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: `{}`", self.var, self.reason, self.text)
+    }
+}
+
+impl error::Error for FromPhraseError {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum QueryError {
+    Parse(ParseError),
+    FromPhrase(FromPhraseError),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryError::Parse(ref e) => e.fmt(f),
+            QueryError::FromPhrase(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for QueryError {}
+
+impl From<ParseError> for QueryError {
+    fn from(e: ParseError) -> QueryError {
+        QueryError::Parse(e)
+    }
+}
+
+impl From<FromPhraseError> for QueryError {
+    fn from(e: FromPhraseError) -> QueryError {
+        QueryError::FromPhrase(e)
+    }
+}
+
+pub(crate) fn convert<T>(
+    var: &str,
+    term: Option<&Term>,
+    string_cache: &StringCache,
+) -> Result<T, FromPhraseError>
+where
+    T: FromTerm,
+{
+    let error = |text: String, reason: &str| FromPhraseError {
+        var: var.to_string(),
+        text,
+        reason: reason.to_string(),
+    };
+
+    match term {
+        Some(term) => T::from_term(term, string_cache)
+            .map_err(|reason| error(term_to_string(term, string_cache), reason)),
+        None => Err(error(String::new(), "unbound variable")),
+    }
+}
+
+impl FromTerm for Atom {
+    fn from_term(term: &Term, _: &StringCache) -> Result<Atom, &'static str> {
+        term.as_atom().ok_or("expected an atom")
+    }
+}
+
+impl FromTerm for String {
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<String, &'static str> {
+        Atom::from_term(term, string_cache).map(|atom| string_cache.from_atom(atom).into_owned())
+    }
+}
+
+impl FromTerm for Term {
+    fn from_term(term: &Term, _: &StringCache) -> Result<Term, &'static str> {
+        Ok(term.clone())
+    }
+}
+
+impl<T: FromTerm> FromTerm for Vec<T> {
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<Vec<T>, &'static str> {
+        term.as_list()
+            .ok_or("expected a list")?
+            .iter()
+            .map(|t| T::from_term(t, string_cache))
+            .collect()
+    }
+}
+
+impl FromTerm for i64 {
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<i64, &'static str> {
+        Atom::from_term(term, string_cache)?
+            .as_int()
+            .ok_or("expected an integer")
+    }
+}
+
+macro_rules! from_term_int {
+    ($($t:ty),*) => {
+        $(
+            impl FromTerm for $t {
+                fn from_term(term: &Term, string_cache: &StringCache) -> Result<$t, &'static str> {
+                    <$t>::try_from(i64::from_term(term, string_cache)?)
+                        .map_err(|_| "integer out of range")
+                }
+            }
+        )*
+    };
+}
+
+from_term_int!(i8, i16, i32, isize, u8, u16, u32, u64, usize);
+
+impl FromTerm for f64 {
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<f64, &'static str> {
+        Atom::from_term(term, string_cache)?
+            .as_float()
+            .ok_or("expected a number")
+    }
+}
+
+impl FromTerm for f32 {
+    fn from_term(term: &Term, string_cache: &StringCache) -> Result<f32, &'static str> {
+        f64::from_term(term, string_cache).map(|v| v as f32)
+    }
+}
@@ -0,0 +1,392 @@
+use super::state::{self, Change};
+use super::{
+    branch_rng, complete_match, match_variables_with_existing, test_match_without_variables, Match,
+    MatchMode, Phrase, RandOrder, Rule, RuleInputs, SideInput, State,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::vec::Vec;
+
+/// A network of partial matches for each rule, kept up to date with the
+/// changes recorded by `State`, so that matching a rule doesn't need to search
+/// the state again.
+pub(crate) struct Rete {
+    networks: HashMap<i32, Network>,
+    // the phrases that match an input of any network
+    phrases: HashMap<u64, Phrase>,
+}
+
+// The matches of a rule's forward inputs. Level k of the network holds the
+// states matching forward input k on its own (alpha), and the permutations of
+// distinct states matching forward inputs 0..=k together (beta).
+struct Network {
+    inputs: Vec<Phrase>,
+    alpha: Vec<HashSet<u64>>,
+    beta: Vec<Vec<Partial>>,
+}
+
+struct Partial {
+    ids: Vec<u64>,
+    variables: Vec<Match>,
+}
+
+impl Rete {
+    pub fn new(rules: &[Rule], state: &State) -> Rete {
+        let mut networks = HashMap::new();
+
+        for r in rules.iter() {
+            let inputs = RuleInputs::new(&r.inputs)
+                .forward_pred
+                .iter()
+                .map(|&i_i| r.inputs[i_i].clone())
+                .collect::<Vec<_>>();
+
+            networks.insert(
+                r.id,
+                Network {
+                    alpha: inputs.iter().map(|_| HashSet::new()).collect(),
+                    beta: inputs.iter().map(|_| vec![]).collect(),
+                    inputs,
+                },
+            );
+        }
+
+        let mut rete = Rete {
+            networks,
+            phrases: HashMap::new(),
+        };
+
+        for s_i in 0..state.len() {
+            rete.add(state, state.id(s_i));
+        }
+
+        rete
+    }
+
+    /// Applies the changes made to `state` since the last sync.
+    pub fn sync(&mut self, state: &mut State) {
+        let changes = state.take_changes();
+
+        // rules often add back a phrase they removed, such as their stage.
+        // treat the added phrase as the removed phrase under a new id, so that
+        // its partial matches don't have to be rebuilt.
+        let mut removed = vec![];
+        let mut renamed = HashMap::new();
+
+        for &change in changes.iter() {
+            match change {
+                Change::Add(id) => {
+                    if let Some(s_i) = state.idx(id) {
+                        if let Some(pos) = removed
+                            .iter()
+                            .position(|old_id| self.phrases[old_id] == state[s_i])
+                        {
+                            renamed.insert(id, removed.swap_remove(pos));
+                        }
+                    }
+                }
+                Change::Remove(id) => {
+                    if self.phrases.contains_key(&id) {
+                        removed.push(id);
+                    }
+                }
+                Change::Clear => removed.clear(),
+            }
+        }
+
+        let old_ids = renamed.values().cloned().collect::<HashSet<_>>();
+
+        for change in changes.into_iter() {
+            match change {
+                Change::Add(id) => match renamed.get(&id) {
+                    Some(&old_id) => self.rename(old_id, id),
+                    None => self.add(state, id),
+                },
+                Change::Remove(id) => {
+                    if !old_ids.contains(&id) {
+                        self.remove(id);
+                    }
+                }
+                Change::Clear => {
+                    self.phrases.clear();
+
+                    for network in self.networks.values_mut() {
+                        network.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, state: &State, id: u64) {
+        // the phrase may have been removed again before the sync
+        if let Some(s_i) = state.idx(id) {
+            self.phrases.insert(id, state[s_i].clone());
+
+            let mut found = false;
+
+            for network in self.networks.values_mut() {
+                found |= network.add(&self.phrases, id);
+            }
+
+            if !found {
+                self.phrases.remove(&id);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if self.phrases.remove(&id).is_some() {
+            for network in self.networks.values_mut() {
+                network.remove(id);
+            }
+        }
+    }
+
+    fn rename(&mut self, old_id: u64, id: u64) {
+        let phrase = self.phrases.remove(&old_id).expect("phrase");
+        self.phrases.insert(id, phrase);
+
+        for network in self.networks.values_mut() {
+            network.rename(old_id, id);
+        }
+    }
+
+    /// Like `rule_matches_state`, but only tries the complete matches of the
+    /// rule's forward inputs, in the same order that `rule_matches_state`
+    /// would find them.
+    pub fn rule_matches_state<F>(
+        &self,
+        r: &Rule,
+        state: &State,
+        seed: u64,
+        mode: MatchMode,
+        side_input: &mut F,
+    ) -> Option<(Rule, Vec<Match>, Vec<usize>)>
+    where
+        F: SideInput,
+    {
+        let network = self.networks.get(&r.id).expect("network");
+        let rule_inputs = RuleInputs::new(&r.inputs);
+
+        let mut try_match = |states_matched: Vec<usize>, variables_matched: &Vec<Match>| {
+            complete_match(
+                r,
+                &rule_inputs,
+                state,
+                &states_matched,
+                variables_matched,
+                mode,
+                side_input,
+            )
+            .map(|(rule, variables)| (rule, variables, states_matched))
+        };
+
+        let complete = match network.beta.last() {
+            Some(complete) => complete,
+            None => return try_match(vec![], &vec![]),
+        };
+
+        let matches = complete.iter().collect::<Vec<_>>();
+
+        visit(
+            &network.inputs,
+            state,
+            seed,
+            &mut vec![],
+            matches,
+            &mut try_match,
+        )
+    }
+}
+
+// Tries the complete matches in the order that the search in
+// rule_matches_state would find them. At each level, the candidates for the
+// input are visited in the random order given by the states matched so far, so
+// the matches are grouped by the state matched at that level, and the groups
+// are visited in that order.
+fn visit<'a, T>(
+    inputs: &[Phrase],
+    state: &State,
+    seed: u64,
+    path: &mut Vec<usize>,
+    matches: Vec<&'a Partial>,
+    try_match: &mut T,
+) -> Option<(Rule, Vec<Match>, Vec<usize>)>
+where
+    T: FnMut(Vec<usize>, &'a Vec<Match>) -> Option<(Rule, Vec<Match>, Vec<usize>)>,
+{
+    let level = path.len();
+
+    if level == inputs.len() {
+        // the states matched by each complete match are distinct
+        return try_match(path.clone(), &matches[0].variables);
+    }
+
+    let input = &inputs[level];
+    let order = RandOrder::new(state.candidate_count(input), &mut branch_rng(seed, path));
+
+    let mut ranked = matches
+        .into_iter()
+        .map(|partial| {
+            let s_i = state.idx(partial.ids[level]).expect("idx");
+            (
+                order.rank(state.candidate_position(input, s_i)),
+                s_i,
+                partial,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    while !ranked.is_empty() {
+        let first = ranked.iter().map(|&(rank, _, _)| rank).min().expect("min");
+
+        let (group, rest): (Vec<_>, Vec<_>) =
+            ranked.into_iter().partition(|&(rank, _, _)| rank == first);
+        ranked = rest;
+
+        path.push(group[0].1);
+        let group = group.into_iter().map(|(_, _, partial)| partial).collect();
+        let result = visit(inputs, state, seed, path, group, try_match);
+        path.pop();
+
+        if result.is_some() {
+            return result;
+        }
+    }
+
+    None
+}
+
+impl Network {
+    // Returns whether the phrase matches any of the network's inputs.
+    fn add(&mut self, phrases: &HashMap<u64, Phrase>, id: u64) -> bool {
+        let phrase = &phrases[&id];
+
+        let mut passes = vec![];
+
+        for (level, input) in self.inputs.iter().enumerate() {
+            let pass =
+                state::is_candidate(input, phrase) && test_match_without_variables(input, phrase);
+
+            if pass {
+                self.alpha[level].insert(id);
+            }
+
+            passes.push(pass);
+        }
+
+        if !passes.iter().any(|&pass| pass) {
+            return false;
+        }
+
+        // the new partial matches at each level either match the new state at
+        // that level, or extend a new partial match from the level above.
+        let mut added: Vec<Vec<Partial>> = vec![];
+
+        for (level, input) in self.inputs.iter().enumerate() {
+            let mut new_partials = vec![];
+
+            if passes[level] {
+                if level == 0 {
+                    if let Some(variables) = match_variables_with_existing(input, phrase, &[]) {
+                        new_partials.push(Partial {
+                            ids: vec![id],
+                            variables,
+                        });
+                    }
+                } else {
+                    for partial in self.beta[level - 1].iter() {
+                        if let Some(partial) = partial.extend(input, id, phrase) {
+                            new_partials.push(partial);
+                        }
+                    }
+                }
+            }
+
+            if level > 0 {
+                for partial in added[level - 1].iter() {
+                    for &other_id in self.alpha[level].iter() {
+                        if let Some(partial) = partial.extend(input, other_id, &phrases[&other_id])
+                        {
+                            new_partials.push(partial);
+                        }
+                    }
+                }
+            }
+
+            added.push(new_partials);
+        }
+
+        for (level, new_partials) in added.into_iter().enumerate() {
+            self.beta[level].extend(new_partials);
+        }
+
+        true
+    }
+
+    fn remove(&mut self, id: u64) {
+        let mut found = false;
+
+        for alpha in self.alpha.iter_mut() {
+            found |= alpha.remove(&id);
+        }
+
+        if found {
+            for beta in self.beta.iter_mut() {
+                beta.retain(|partial| !partial.ids.contains(&id));
+            }
+        }
+    }
+
+    fn rename(&mut self, old_id: u64, id: u64) {
+        let mut found = false;
+
+        for alpha in self.alpha.iter_mut() {
+            if alpha.remove(&old_id) {
+                alpha.insert(id);
+                found = true;
+            }
+        }
+
+        if found {
+            for partial in self.beta.iter_mut().flat_map(|beta| beta.iter_mut()) {
+                for partial_id in partial
+                    .ids
+                    .iter_mut()
+                    .filter(|partial_id| **partial_id == old_id)
+                {
+                    *partial_id = id;
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for alpha in self.alpha.iter_mut() {
+            alpha.clear();
+        }
+
+        for beta in self.beta.iter_mut() {
+            beta.clear();
+        }
+    }
+}
+
+impl Partial {
+    fn extend(&self, input: &Phrase, id: u64, phrase: &Phrase) -> Option<Partial> {
+        if self.ids.contains(&id) {
+            return None;
+        }
+
+        match_variables_with_existing(input, phrase, &self.variables).map(|mut result| {
+            let mut ids = self.ids.clone();
+            ids.push(id);
+
+            let mut variables = self.variables.clone();
+            variables.append(&mut result);
+
+            Partial { ids, variables }
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use rand::{Error, RngCore};
+
+/// xorshift128, the generator behind `SmallRng`, with its state exposed so
+/// that a saved `Context` carries on with the same random choices when it's
+/// loaded. Seeded with the same bytes, it produces the same sequence as
+/// `SmallRng`.
+#[derive(Clone, Debug)]
+pub(crate) struct XorShiftRng {
+    state: [u32; 4],
+}
+
+impl XorShiftRng {
+    /// `state` must not be all zeros.
+    pub fn from_state(state: [u32; 4]) -> XorShiftRng {
+        assert!(state.iter().any(|&x| x != 0), "zero xorshift state");
+
+        XorShiftRng { state }
+    }
+
+    pub fn state(&self) -> [u32; 4] {
+        self.state
+    }
+}
+
+impl RngCore for XorShiftRng {
+    fn next_u32(&mut self) -> u32 {
+        let [x, y, z, w] = self.state;
+        let t = x ^ (x << 11);
+        self.state = [y, z, w, w ^ (w >> 19) ^ (t ^ (t >> 8))];
+        self.state[3]
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let lo = u64::from(self.next_u32());
+        let hi = u64::from(self.next_u32());
+        (hi << 32) | lo
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let n = chunk.len();
+
+            if n > 4 {
+                chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..n]);
+            } else {
+                chunk.copy_from_slice(&self.next_u32().to_le_bytes()[..n]);
+            }
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -0,0 +1,471 @@
+use super::number::Number;
+use super::parser::{self, ParseError};
+use super::types::Types;
+use super::{
+    check_state_phrase, first_token, phrase_to_string, Atom, AtomKind, Phrase, StringCache, Term,
+    Token,
+};
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+const MAGIC: &[u8] = b"ceptre\0";
+const VERSION: u8 = 1;
+
+const SYMBOL: u8 = 0;
+const INT: u8 = 1;
+const FLOAT: u8 = 2;
+const LIST: u8 = 3;
+const CONS: u8 = 4;
+
+// Lists nested deeper than this are refused, so that a damaged save can't
+// overflow the stack while it is read.
+const MAX_DEPTH: usize = 256;
+
+/// How `Context::save` writes the state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SaveFormat {
+    /// One phrase per line, as initial state in a program, after comments
+    /// holding the random number generator, rule order and quiescence.
+    Text,
+    /// A compact encoding, which also keeps atoms that couldn't be parsed
+    /// back from text.
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Parse(ParseError),
+    /// The save is truncated, or wasn't written by `Context::save`.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref e) => e.fmt(f),
+            LoadError::Parse(ref e) => e.fmt(f),
+            LoadError::Invalid(reason) => write!(f, "invalid save: {}", reason),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+impl From<ParseError> for LoadError {
+    fn from(e: ParseError) -> LoadError {
+        LoadError::Parse(e)
+    }
+}
+
+/// The parts of a `Context` that change as its rules fire.
+pub(crate) struct Snapshot {
+    pub phrases: Vec<Phrase>,
+    /// None if a text save doesn't give it, to keep the current generator.
+    pub rng: Option<[u32; 4]>,
+    /// The ids of the rules in the order they were last shuffled into, which
+    /// decides how they are shuffled next.
+    pub rule_ids: Option<Vec<i32>>,
+    pub quiescence: bool,
+}
+
+pub(crate) fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Fails if a phrase would be read back as a statement rather than as state,
+// such as `type x`.
+pub(crate) fn to_text(
+    state: &[Phrase],
+    rng: [u32; 4],
+    rule_ids: &[i32],
+    quiescence: bool,
+    string_cache: &StringCache,
+) -> io::Result<String> {
+    let mut text = format!(
+        "// rng {:08x} {:08x} {:08x} {:08x}\n// rule-order",
+        rng[0], rng[1], rng[2], rng[3]
+    );
+
+    for id in rule_ids.iter() {
+        text.push_str(&format!(" {}", id));
+    }
+
+    text.push_str(&format!("\n// quiescence {}\n", quiescence));
+
+    for phrase in state.iter() {
+        let line = phrase_to_string(phrase, string_cache);
+
+        let first = first_token(phrase)
+            .filter(|token| !token.is_negated)
+            .map(|token| token.as_str(string_cache));
+
+        if first.is_some_and(|s| s == "type" || s == "#include") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` can't be saved as text, since it would be read as a statement",
+                    line
+                ),
+            ));
+        }
+
+        text.push_str(&line);
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+// The header is the comments before the first phrase. Other comments are
+// ignored, as they would be in a program. The phrases are checked against
+// `types`, as `append_state` would check them.
+pub(crate) fn from_text(
+    text: &str,
+    types: &Types,
+    string_cache: &mut StringCache,
+) -> Result<Snapshot, LoadError> {
+    let mut rng = None;
+    let mut rule_ids = None;
+    let mut quiescence = false;
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let words = match line.get(..2) {
+            Some("//") => line[2..].split_whitespace().collect::<Vec<_>>(),
+            _ => break,
+        };
+
+        match words.first() {
+            Some(&"rng") => {
+                let state = words[1..]
+                    .iter()
+                    .map(|w| u32::from_str_radix(w, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| {
+                        LoadError::Invalid("expected 4 hexadecimal numbers after `rng`")
+                    })?;
+
+                match state[..] {
+                    [a, b, c, d] if state.iter().any(|&x| x != 0) => rng = Some([a, b, c, d]),
+                    _ => {
+                        return Err(LoadError::Invalid(
+                            "expected 4 hexadecimal numbers after `rng`",
+                        ))
+                    }
+                }
+            }
+            Some(&"rule-order") => {
+                let ids = words[1..]
+                    .iter()
+                    .map(|w| w.parse::<i32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| LoadError::Invalid("expected rule ids after `rule-order`"))?;
+
+                rule_ids = Some(ids);
+            }
+            Some(&"quiescence") => {
+                quiescence = match words.get(1) {
+                    Some(&"true") => true,
+                    Some(&"false") => false,
+                    _ => {
+                        return Err(LoadError::Invalid(
+                            "expected `true` or `false` after `quiescence`",
+                        ))
+                    }
+                };
+            }
+            _ => (),
+        }
+    }
+
+    let program = parser::parse(text, None, string_cache)?;
+
+    if let Some(rule) = program.rules.first() {
+        return Err(LoadError::Parse(ParseError {
+            file: None,
+            line: rule.span.line,
+            column: rule.span.column,
+            text: "=".to_string(),
+            reason: "a saved state can't contain rules".to_string(),
+        }));
+    }
+
+    if let Some(decl) = program
+        .types
+        .values()
+        .min_by_key(|decl| (decl.span.line, decl.span.column))
+    {
+        return Err(LoadError::Parse(ParseError {
+            file: None,
+            line: decl.span.line,
+            column: decl.span.column,
+            text: "type".to_string(),
+            reason: "a saved state can't contain type declarations".to_string(),
+        }));
+    }
+
+    for (phrase, span) in program.state.iter().zip(program.state_spans.iter()) {
+        check_state_phrase(phrase, types, string_cache).map_err(|e| ParseError {
+            line: span.line,
+            column: span.column,
+            ..e
+        })?;
+    }
+
+    Ok(Snapshot {
+        phrases: program.state,
+        rng,
+        rule_ids,
+        quiescence,
+    })
+}
+
+// The binary format is the magic bytes and version, the generator's state as
+// four u32s, the rule ids, the quiescence as a byte, the text of every symbol
+// in the state, then the phrases. Numbers are little-endian.
+pub(crate) fn to_binary(
+    state: &[Phrase],
+    rng: [u32; 4],
+    rule_ids: &[i32],
+    quiescence: bool,
+    string_cache: &StringCache,
+) -> Vec<u8> {
+    let mut symbols = vec![];
+    let mut symbol_ids = HashMap::new();
+
+    let mut phrases = vec![];
+    write_u32(&mut phrases, state.len());
+
+    for phrase in state.iter() {
+        write_terms(&mut phrases, phrase, &mut |token: &Token| {
+            let mut text = string_cache.from_atom(token.string).into_owned();
+            if token.is_negated {
+                text.insert(0, '!');
+            }
+
+            let next_id = symbols.len();
+            *symbol_ids.entry(text.clone()).or_insert_with(|| {
+                symbols.push(text);
+                next_id
+            })
+        });
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+
+    for &x in rng.iter() {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    write_u32(&mut bytes, rule_ids.len());
+    for &id in rule_ids.iter() {
+        bytes.extend_from_slice(&id.to_le_bytes());
+    }
+
+    bytes.push(quiescence as u8);
+
+    write_u32(&mut bytes, symbols.len());
+    for symbol in symbols.iter() {
+        write_u32(&mut bytes, symbol.len());
+        bytes.extend_from_slice(symbol.as_bytes());
+    }
+
+    bytes.extend_from_slice(&phrases);
+    bytes
+}
+
+fn write_u32(bytes: &mut Vec<u8>, v: usize) {
+    bytes.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+fn write_terms<F>(bytes: &mut Vec<u8>, terms: &[Term], symbol_id: &mut F)
+where
+    F: FnMut(&Token) -> usize,
+{
+    write_u32(bytes, terms.len());
+
+    for term in terms.iter() {
+        write_term(bytes, term, symbol_id);
+    }
+}
+
+fn write_term<F>(bytes: &mut Vec<u8>, term: &Term, symbol_id: &mut F)
+where
+    F: FnMut(&Token) -> usize,
+{
+    match *term {
+        Term::Atom(ref token) => match token.string.kind {
+            AtomKind::Symbol(_) => {
+                bytes.push(SYMBOL);
+                write_u32(bytes, symbol_id(token));
+            }
+            AtomKind::Number(Number::Int(v)) => {
+                bytes.push(INT);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            AtomKind::Number(Number::Float(v)) => {
+                bytes.push(FLOAT);
+                bytes.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+        },
+        Term::List(ref terms) => {
+            bytes.push(LIST);
+            write_terms(bytes, terms, symbol_id);
+        }
+        Term::Cons(ref terms, ref tail) => {
+            bytes.push(CONS);
+            write_terms(bytes, terms, symbol_id);
+            write_term(bytes, tail, symbol_id);
+        }
+    }
+}
+
+pub(crate) fn from_binary(
+    bytes: &[u8],
+    types: &Types,
+    string_cache: &mut StringCache,
+) -> Result<Snapshot, LoadError> {
+    if !is_binary(bytes) {
+        return Err(LoadError::Invalid("not a binary save"));
+    }
+
+    let mut reader = Reader {
+        bytes: &bytes[MAGIC.len()..],
+    };
+
+    if reader.take(1)?[0] != VERSION {
+        return Err(LoadError::Invalid("unsupported version"));
+    }
+
+    let mut rng = [0; 4];
+    for x in rng.iter_mut() {
+        *x = reader.u32()?;
+    }
+
+    if rng.iter().all(|&x| x == 0) {
+        return Err(LoadError::Invalid("zero rng state"));
+    }
+
+    let mut rule_ids = vec![];
+    for _ in 0..reader.u32()? {
+        rule_ids.push(reader.u32()? as i32);
+    }
+
+    let quiescence = match reader.take(1)?[0] {
+        0 => false,
+        1 => true,
+        _ => return Err(LoadError::Invalid("expected 0 or 1 for quiescence")),
+    };
+
+    let mut symbols = vec![];
+    for _ in 0..reader.u32()? {
+        let len = reader.u32()? as usize;
+        let text = str::from_utf8(reader.take(len)?)
+            .map_err(|_| LoadError::Invalid("symbol isn't UTF-8"))?;
+
+        if text.is_empty() || text == "!" {
+            return Err(LoadError::Invalid("empty symbol"));
+        }
+
+        symbols.push(Token::new(text, string_cache));
+    }
+
+    let mut phrases = vec![];
+    for _ in 0..reader.u32()? {
+        let phrase = reader.terms(&symbols, 0)?;
+
+        if phrase.is_empty() {
+            return Err(LoadError::Invalid("empty phrase"));
+        }
+
+        check_state_phrase(&phrase, types, string_cache)?;
+        phrases.push(phrase);
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(LoadError::Invalid("unexpected bytes after the last phrase"));
+    }
+
+    Ok(Snapshot {
+        phrases,
+        rng: Some(rng),
+        rule_ids: Some(rule_ids),
+        quiescence,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() < len {
+            return Err(LoadError::Invalid("unexpected end of save"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // `depth` is the number of lists the terms are in.
+    fn terms(&mut self, symbols: &[Token], depth: usize) -> Result<Vec<Term>, LoadError> {
+        let len = self.u32()?;
+
+        (0..len).map(|_| self.term(symbols, depth)).collect()
+    }
+
+    fn term(&mut self, symbols: &[Token], depth: usize) -> Result<Term, LoadError> {
+        let term = match self.take(1)?[0] {
+            SYMBOL => {
+                let id = self.u32()? as usize;
+                let token = symbols
+                    .get(id)
+                    .ok_or(LoadError::Invalid("unknown symbol"))?;
+                Term::Atom(token.clone())
+            }
+            INT => Term::Atom(Token::from_atom(Atom::int(self.u64()? as i64))),
+            FLOAT => Term::Atom(Token::from_atom(Atom::float(f64::from_bits(self.u64()?)))),
+            LIST | CONS if depth == MAX_DEPTH => {
+                return Err(LoadError::Invalid("lists nested too deeply"))
+            }
+            LIST => Term::List(self.terms(symbols, depth + 1)?),
+            CONS => {
+                let terms = self.terms(symbols, depth + 1)?;
+                Term::Cons(terms, Box::new(self.term(symbols, depth + 1)?))
+            }
+            _ => return Err(LoadError::Invalid("unknown term")),
+        };
+
+        Ok(term)
+    }
+}
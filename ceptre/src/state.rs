@@ -0,0 +1,196 @@
+use super::{first_token, Atom, Phrase};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Deref;
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    // None for phrases starting with a list
+    atom: Option<Atom>,
+    arity: usize,
+}
+
+impl Key {
+    fn new(phrase: &Phrase) -> Key {
+        Key {
+            atom: first_token(phrase).map(|t| t.string),
+            arity: phrase.len(),
+        }
+    }
+}
+
+/// The phrases of a `Context`, indexed by leading atom and arity so that
+/// rule inputs are only tested against phrases that could match them.
+///
+/// Derefs to a slice of phrases for reading. Phrases may be reordered when
+/// others are removed.
+pub struct State {
+    phrases: Vec<Phrase>,
+    index: HashMap<Key, Vec<usize>>,
+    // for each phrase, its position in its index entry
+    positions: Vec<usize>,
+    // for each phrase, an id that doesn't change when other phrases are removed
+    ids: Vec<u64>,
+    id_to_idx: HashMap<u64, usize>,
+    next_id: u64,
+    // changes since the last call to take_changes, if recording
+    changes: Option<Vec<Change>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Change {
+    Add(u64),
+    Remove(u64),
+    Clear,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::new()
+    }
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            phrases: vec![],
+            index: HashMap::new(),
+            positions: vec![],
+            ids: vec![],
+            id_to_idx: HashMap::new(),
+            next_id: 0,
+            changes: None,
+        }
+    }
+
+    pub fn push(&mut self, phrase: Phrase) {
+        let idx = self.phrases.len();
+        let entry = self.index.entry(Key::new(&phrase)).or_default();
+
+        self.positions.push(entry.len());
+        entry.push(idx);
+        self.phrases.push(phrase);
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.ids.push(id);
+        self.id_to_idx.insert(id, idx);
+
+        if let Some(ref mut changes) = self.changes {
+            changes.push(Change::Add(id));
+        }
+    }
+
+    /// Removes the phrase at `idx`, replacing it with the last phrase.
+    pub fn swap_remove(&mut self, idx: usize) -> Phrase {
+        let key = Key::new(&self.phrases[idx]);
+        let pos = self.positions[idx];
+
+        {
+            let entry = self.index.get_mut(&key).expect("entry");
+            entry.swap_remove(pos);
+
+            if pos < entry.len() {
+                self.positions[entry[pos]] = pos;
+            }
+        }
+
+        let last = self.phrases.len() - 1;
+        if idx != last {
+            let key = Key::new(&self.phrases[last]);
+            self.index.get_mut(&key).expect("entry")[self.positions[last]] = idx;
+            self.id_to_idx.insert(self.ids[last], idx);
+        }
+
+        let id = self.ids.swap_remove(idx);
+        self.id_to_idx.remove(&id);
+
+        if let Some(ref mut changes) = self.changes {
+            changes.push(Change::Remove(id));
+        }
+
+        self.positions.swap_remove(idx);
+        self.phrases.swap_remove(idx)
+    }
+
+    pub fn clear(&mut self) {
+        self.phrases.clear();
+        self.index.clear();
+        self.positions.clear();
+        self.ids.clear();
+        self.id_to_idx.clear();
+
+        if let Some(ref mut changes) = self.changes {
+            changes.push(Change::Clear);
+        }
+    }
+
+    pub(crate) fn id(&self, idx: usize) -> u64 {
+        self.ids[idx]
+    }
+
+    pub(crate) fn idx(&self, id: u64) -> Option<usize> {
+        self.id_to_idx.get(&id).cloned()
+    }
+
+    /// The position of the phrase at `idx` in the result of `candidates`.
+    pub(crate) fn candidate_position(&self, input: &Phrase, idx: usize) -> usize {
+        if input[0].is_var() {
+            idx
+        } else {
+            self.positions[idx]
+        }
+    }
+
+    pub(crate) fn record_changes(&mut self, record: bool) {
+        self.changes = if record { Some(vec![]) } else { None };
+    }
+
+    pub(crate) fn take_changes(&mut self) -> Vec<Change> {
+        match self.changes {
+            Some(ref mut changes) => mem::take(changes),
+            None => vec![],
+        }
+    }
+
+    /// The number of phrases returned by `candidates`.
+    pub(crate) fn candidate_count(&self, input: &Phrase) -> usize {
+        if input[0].is_var() {
+            return self.phrases.len();
+        }
+
+        self.index
+            .get(&Key::new(input))
+            .map_or(0, |entry| entry.len())
+    }
+
+    /// Returns the indices of all phrases that could match `input`. Inputs
+    /// starting with a variable can't use the index, so all phrases are returned.
+    pub(crate) fn candidates<'a>(&'a self, input: &Phrase) -> Cow<'a, [usize]> {
+        if input[0].is_var() {
+            return Cow::Owned((0..self.phrases.len()).collect());
+        }
+
+        match self.index.get(&Key::new(input)) {
+            Some(entry) => Cow::Borrowed(entry),
+            None => Cow::Borrowed(&[]),
+        }
+    }
+}
+
+impl Deref for State {
+    type Target = [Phrase];
+
+    fn deref(&self) -> &[Phrase] {
+        &self.phrases
+    }
+}
+
+/// Whether `phrase` is one of the candidates for `input`.
+pub(crate) fn is_candidate(input: &Phrase, phrase: &Phrase) -> bool {
+    input[0].is_var() || Key::new(input) == Key::new(phrase)
+}
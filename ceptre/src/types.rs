@@ -0,0 +1,142 @@
+use super::parser::Span;
+use super::{
+    first_token, is_whole_phrase_var, phrase_to_string, term_to_string, Atom, Phrase, StringCache,
+    Term,
+};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// The type of an argument in a `type` declaration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgType {
+    Int,
+    /// Any number, so `0.5` and `1` are both floats.
+    Float,
+    /// A symbol that isn't a number.
+    Atom,
+    List,
+    Any,
+}
+
+impl ArgType {
+    pub(crate) fn parse(text: &str) -> Option<ArgType> {
+        match text {
+            "int" => Some(ArgType::Int),
+            "float" => Some(ArgType::Float),
+            "atom" => Some(ArgType::Atom),
+            "list" => Some(ArgType::List),
+            "any" => Some(ArgType::Any),
+            _ => None,
+        }
+    }
+
+    fn with_article(self) -> String {
+        match self {
+            ArgType::Int | ArgType::Atom | ArgType::Any => format!("an {}", self),
+            ArgType::Float | ArgType::List => format!("a {}", self),
+        }
+    }
+
+    /// Whether `term`, which isn't a variable, has the type.
+    fn accepts(self, term: &Term) -> bool {
+        match self {
+            ArgType::Int => term.as_int().is_some(),
+            ArgType::Float => term.as_float().is_some(),
+            ArgType::Atom => term.as_atom().is_some_and(|atom| !atom.is_number()),
+            ArgType::List => match *term {
+                Term::List(_) | Term::Cons(..) => true,
+                Term::Atom(_) => false,
+            },
+            ArgType::Any => true,
+        }
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ArgType::Int => "int",
+            ArgType::Float => "float",
+            ArgType::Atom => "atom",
+            ArgType::List => "list",
+            ArgType::Any => "any",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The arguments of a predicate, declared with a line such as
+/// `type note instrument:int pos:int desc:atom`.
+///
+/// Every phrase of the predicate in the program must have the declared number
+/// of arguments, and arguments that aren't variables must have the declared
+/// types, wherever the declaration is written. `append_state`,
+/// `append_phrase`, `Context::pattern` and `Context::query` are checked in the
+/// same way, so a `FromPhrase` type with the wrong number of fields is an
+/// error rather than matching nothing. A statement starting with `type` is
+/// always a declaration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeDecl {
+    pub name: String,
+    /// The name and type of each argument, in order.
+    pub args: Vec<(String, ArgType)>,
+    /// Where the declaration was written.
+    pub span: Span,
+}
+
+pub(crate) type Types = HashMap<Atom, TypeDecl>;
+
+/// A phrase that doesn't match its predicate's declaration, with the text
+/// of the offending term.
+pub(crate) struct TypeError {
+    pub text: String,
+    pub reason: String,
+}
+
+/// Checks the arity of a phrase of a declared predicate, and the type of
+/// each argument that isn't a variable.
+pub(crate) fn check_phrase(
+    phrase: &Phrase,
+    types: &Types,
+    string_cache: &StringCache,
+) -> Result<(), TypeError> {
+    let decl = match first_token(phrase) {
+        Some(token) if !is_whole_phrase_var(phrase) => match types.get(&token.string) {
+            Some(decl) => decl,
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    let args = &phrase[1..];
+
+    if args.len() != decl.args.len() {
+        return Err(TypeError {
+            text: phrase_to_string(phrase, string_cache),
+            reason: format!(
+                "`{}` is declared with {} arguments, not {}",
+                decl.name,
+                decl.args.len(),
+                args.len()
+            ),
+        });
+    }
+
+    for (term, &(ref arg, arg_type)) in args.iter().zip(decl.args.iter()) {
+        if !term.is_var() && !arg_type.accepts(term) {
+            return Err(TypeError {
+                text: term_to_string(term, string_cache),
+                reason: format!(
+                    "expected {} for `{}` of `{}`",
+                    arg_type.with_article(),
+                    arg,
+                    decl.name
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+extern crate ceptre;
+
+use ceptre::{Atom, Context};
+
+use std::collections::HashSet;
+
+// Runs a rule that calculates `result` from the given expression, returning
+// the resulting phrase as text, or None if the expression failed.
+fn eval(expr: &str) -> Option<String> {
+    let mut context =
+        Context::from_text_with_seed(&format!("#eval . {} = result X", expr), 0).unwrap();
+    context.append_state("#eval").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("result X").unwrap();
+    let result = context.find(&pattern).next();
+
+    result.map(|b| {
+        format!(
+            "result {}",
+            ceptre::term_to_string(b.get("X").unwrap(), &context.string_cache)
+        )
+    })
+}
+
+#[test]
+fn integer_arithmetic_stays_integer() {
+    assert_eq!(eval("+ 7 9 X"), Some("result 16".to_string()));
+    assert_eq!(eval("+ 7 X 16"), Some("result 9".to_string()));
+    assert_eq!(eval("+ X 9 16"), Some("result 7".to_string()));
+    assert_eq!(eval("%% 17 16 X"), Some("result 1".to_string()));
+}
+
+#[test]
+fn float_arithmetic() {
+    assert_eq!(eval("+ 0.5 1 X"), Some("result 1.5".to_string()));
+    assert_eq!(eval("+ 1.5 0.5 X"), Some("result 2.0".to_string()));
+    assert_eq!(eval("+ -0.5 X 1"), Some("result 1.5".to_string()));
+}
+
+#[test]
+fn symbols_are_not_numbers() {
+    assert_eq!(eval("+ a 1 X"), None);
+    assert_eq!(eval("+ 1st 1 X"), None);
+}
+
+#[test]
+fn numbers_are_typed() {
+    let mut context = Context::from_text_with_seed(
+        "count 0\n\
+         \n\
+         #tick . count N . + N 1 N2 = count N2\n\
+         #half . count N . + N 0.5 N2 = count N2",
+        0,
+    )
+    .unwrap();
+
+    context.append_state("#tick").unwrap();
+    context.append_state("#tick").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("count N").unwrap();
+    let count = context.find(&pattern).next().unwrap();
+    assert_eq!(count.int("N"), Some(2));
+
+    context.append_state("#half").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("count N").unwrap();
+    let count = context.find(&pattern).next().unwrap();
+    assert_eq!(count.int("N"), None);
+    assert_eq!(count.float("N"), Some(2.5));
+}
+
+#[test]
+fn integers_match_equal_floats() {
+    let mut context = Context::from_text_with_seed("#check . value 2 = matched", 0).unwrap();
+
+    context.append_state("value 2.0").unwrap();
+    context.append_state("#check").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("matched").unwrap();
+    assert!(context.find(&pattern).next().is_some());
+    let pattern = context.pattern("value 2").unwrap();
+    assert!(context.find(&pattern).next().is_none());
+}
+
+#[test]
+fn equal_numbers_hash_the_same() {
+    // 2^53 + 1 rounds to 2^53 as a float
+    let atoms = vec![Atom::int(9_007_199_254_740_993), Atom::int(0)]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    assert!(atoms.contains(&Atom::float(9_007_199_254_740_992.0)));
+    assert!(atoms.contains(&Atom::float(-0.0)));
+}
+
+fn result(v: &str) -> Option<String> {
+    Some(format!("result {}", v))
+}
+
+#[test]
+fn subtraction_solves_each_argument() {
+    assert_eq!(eval("- 7 9 X"), result("-2"));
+    assert_eq!(eval("- 7 X 3"), result("4"));
+    assert_eq!(eval("- X 9 3"), result("12"));
+    assert_eq!(eval("- 7 4 3 . == 0 X"), result("0"));
+    assert_eq!(eval("- 7 4 2 . == 0 X"), None);
+}
+
+#[test]
+fn multiplication_solves_each_argument() {
+    assert_eq!(eval("* 3 4 X"), result("12"));
+    assert_eq!(eval("* 3 X 12"), result("4"));
+    assert_eq!(eval("* X 4 12"), result("3"));
+    assert_eq!(eval("* X 4 6"), result("1.5"));
+    assert_eq!(eval("* X 0 6"), None);
+}
+
+#[test]
+fn division_solves_each_argument() {
+    assert_eq!(eval("/ 12 4 X"), result("3"));
+    assert_eq!(eval("/ 7 2 X"), result("3.5"));
+    assert_eq!(eval("/ 12 X 3"), result("4"));
+    assert_eq!(eval("/ X 4 3"), result("12"));
+    assert_eq!(eval("/ 1 0 X"), None);
+}
+
+#[test]
+fn integer_division_rounds_down() {
+    assert_eq!(eval("div 7 2 X"), result("3"));
+    assert_eq!(eval("div -7 2 X"), result("-4"));
+    assert_eq!(eval("div 7.5 2 X"), result("3.0"));
+    assert_eq!(eval("div 7 0 X"), None);
+    // the quotient doesn't determine the dividend
+    assert_eq!(eval("div X 2 3"), None);
+}
+
+#[test]
+fn min_max_abs() {
+    assert_eq!(eval("min 3 -4 X"), result("-4"));
+    assert_eq!(eval("max 3 -4 X"), result("3"));
+    assert_eq!(eval("max 3 4.5 X"), result("4.5"));
+    assert_eq!(eval("abs -4 X"), result("4"));
+    assert_eq!(eval("abs -0.5 X"), result("0.5"));
+    assert_eq!(eval("abs -4 4 . == ok X"), result("ok"));
+    assert_eq!(eval("abs X 4"), None);
+}
+
+#[test]
+fn equality() {
+    assert_eq!(eval("== 3 X"), result("3"));
+    assert_eq!(eval("== X foo"), result("foo"));
+    assert_eq!(eval("== 3 3.0 . == yes X"), result("yes"));
+    assert_eq!(eval("== 3 4 . == yes X"), None);
+    assert_eq!(eval("!= 3 4 . == yes X"), result("yes"));
+    assert_eq!(eval("!= foo foo . == yes X"), None);
+    assert_eq!(eval("!= 3 X"), None);
+}
+
+#[test]
+fn modulo_wraps_with_the_sign_of_the_divisor() {
+    assert_eq!(eval("%% 17 16 X"), result("1"));
+    assert_eq!(eval("%% 16 16 X"), result("0"));
+    assert_eq!(eval("%% -1 16 X"), result("15"));
+    assert_eq!(eval("%% -15 16 X"), result("1"));
+    assert_eq!(eval("%% 1 -16 X"), result("-15"));
+    assert_eq!(eval("%% -17 -16 X"), result("-1"));
+    assert_eq!(eval("%% 16.5 16 X"), result("0.5"));
+    assert_eq!(eval("%% -0.5 16 X"), result("15.5"));
+}
+
+#[test]
+fn modulo_checks_when_fully_bound() {
+    assert_eq!(eval("%% 17 16 1 . == yes X"), result("yes"));
+    assert_eq!(eval("%% -1 16 15 . == yes X"), result("yes"));
+    assert_eq!(eval("%% -1 16 -1 . == yes X"), None);
+    assert_eq!(eval("%% 17 16 2 . == yes X"), None);
+}
+
+#[test]
+fn modulo_by_zero_fails() {
+    assert_eq!(eval("%% 17 0 X"), None);
+    assert_eq!(eval("%% 17 0.0 X"), None);
+    assert_eq!(eval("%% 17 0 17 . == yes X"), None);
+}
+
+#[test]
+fn modulo_only_solves_the_remainder() {
+    assert_eq!(eval("%% X 16 1"), None);
+    assert_eq!(eval("%% 17 X 1"), None);
+}
+
+#[test]
+fn main_program_wraps_selected_instrument() {
+    let mut context = Context::from_text_with_seed(include_str!("main.ceptre"), 0).unwrap();
+
+    context.append_state("selected-instrument 0").unwrap();
+    context.append_state("level-instrument-count 3").unwrap();
+    context.append_state("#input-change-left").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("selected-instrument I").unwrap();
+    let selected = context.find(&pattern).next().unwrap();
+    assert_eq!(selected.int("I"), Some(2));
+
+    context.append_state("#input-change-right").unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("selected-instrument I").unwrap();
+    let selected = context.find(&pattern).next().unwrap();
+    assert_eq!(selected.int("I"), Some(0));
+}
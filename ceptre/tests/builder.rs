@@ -0,0 +1,163 @@
+extern crate ceptre;
+
+use ceptre::{Context, Matcher, PhraseBuilder};
+
+fn state_strings(context: &Context) -> Vec<String> {
+    let mut state = context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect::<Vec<_>>();
+    state.sort();
+    state
+}
+
+#[test]
+fn built_phrases_equal_parsed_phrases() {
+    let mut context = Context::from_text("").unwrap();
+
+    let phrases = vec![
+        (
+            PhraseBuilder::new().atom("level-instruments").int(0).list(
+                PhraseBuilder::new().atom("x").list(
+                    PhraseBuilder::new()
+                        .atom("o")
+                        .list(PhraseBuilder::new().atom("x")),
+                ),
+            ),
+            "level-instruments 0 (x (o (x)))",
+        ),
+        (
+            PhraseBuilder::new().atom("#set-beat").int(-3),
+            "#set-beat -3",
+        ),
+        (PhraseBuilder::new().atom("volume").float(1.0), "volume 1.0"),
+        (
+            PhraseBuilder::new()
+                .atom("list")
+                .list(PhraseBuilder::new().atom("a")),
+            "list (a)",
+        ),
+        (
+            PhraseBuilder::new().atom("a").list(PhraseBuilder::new()),
+            "a ()",
+        ),
+    ];
+
+    for (builder, text) in phrases {
+        let phrase = builder.build(&mut context.string_cache).unwrap();
+        context.append_state(text).unwrap();
+
+        let parsed = context.state.last().unwrap();
+        assert_eq!(parsed, &phrase, "{}", text);
+        assert_eq!(
+            ceptre::phrase_to_string(&phrase, &context.string_cache),
+            ceptre::phrase_to_string(parsed, &context.string_cache)
+        );
+    }
+}
+
+#[test]
+fn built_numbers_stay_numbers() {
+    let mut context = Context::from_text("").unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("v")
+        .float(f64::INFINITY)
+        .float(f64::NAN)
+        .int(i64::MIN)
+        .build(&mut context.string_cache)
+        .unwrap();
+
+    assert_eq!(phrase[1].as_float(), Some(f64::INFINITY));
+    assert!(phrase[2].as_float().unwrap().is_nan());
+    assert_eq!(phrase[3].as_int(), Some(i64::MIN));
+}
+
+#[test]
+fn appended_phrases_fire_rules() {
+    let mut context = Context::from_text("count N . + N 1 N2 = total N2").unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("count")
+        .int(4)
+        .build(&mut context.string_cache)
+        .unwrap();
+    context.append_phrase(phrase).unwrap();
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    assert_eq!(state_strings(&context), vec!["total 5"]);
+}
+
+#[test]
+fn retract_removes_one_phrase() {
+    let mut context = Context::from_text("a 1 . a 1 . a 2").unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("a")
+        .int(1)
+        .build(&mut context.string_cache)
+        .unwrap();
+    assert!(context.retract(&phrase));
+    assert_eq!(state_strings(&context), vec!["a 1", "a 2"]);
+
+    assert!(context.retract(&phrase));
+    assert!(!context.retract(&phrase));
+    assert_eq!(state_strings(&context), vec!["a 2"]);
+}
+
+#[test]
+fn retract_matching_removes_matched_phrases() {
+    for &matcher in [Matcher::Indexed, Matcher::Incremental].iter() {
+        let mut context = Context::from_text(
+            "note 0 3 first . note 0 4 last . note 1 3 first\n\
+             note-tmp 2 7 first . muted 1\n\
+             note N P D . muted N = gone",
+        )
+        .unwrap();
+        context.set_matcher(matcher);
+
+        let pattern = context.pattern("note-tmp I P D").unwrap();
+        assert_eq!(context.retract_matching(&pattern), 1);
+        let pattern = context.pattern("note I P first . !muted I").unwrap();
+        assert_eq!(context.retract_matching(&pattern), 1);
+        let pattern = context.pattern("note-tmp I P D").unwrap();
+        assert_eq!(context.retract_matching(&pattern), 0);
+        let pattern = context.pattern("!note 5 P D").unwrap();
+        assert_eq!(context.retract_matching(&pattern), 0);
+        assert_eq!(
+            state_strings(&context),
+            vec!["muted 1", "note 0 4 last", "note 1 3 first"]
+        );
+
+        ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+        assert_eq!(state_strings(&context), vec!["gone", "note 0 4 last"]);
+
+        assert!(context.pattern("note I P D =").is_err());
+    }
+}
+
+#[test]
+fn build_rejects_atoms_that_rules_cant_match() {
+    let build = |builder: PhraseBuilder| {
+        let mut context = Context::from_text("").unwrap();
+        builder.build(&mut context.string_cache).is_err()
+    };
+
+    assert!(build(PhraseBuilder::new()));
+    assert!(build(PhraseBuilder::new().list(PhraseBuilder::new())));
+    assert!(build(PhraseBuilder::new().atom("qui")));
+    assert!(build(PhraseBuilder::new().atom("a").atom("")));
+    assert!(build(PhraseBuilder::new().atom("X")));
+    assert!(build(
+        PhraseBuilder::new()
+            .atom("note")
+            .list(PhraseBuilder::new().atom("!muted"))
+    ));
+    assert!(build(PhraseBuilder::new().atom("+").int(1).int(2).int(3)));
+    assert!(build(PhraseBuilder::new().atom("%%").int(1)));
+    assert!(build(PhraseBuilder::new().atom("^collide").int(1)));
+
+    assert!(!build(PhraseBuilder::new().atom("mode").atom("min")));
+    assert!(!build(PhraseBuilder::new().atom("#set-beat").int(1)));
+}
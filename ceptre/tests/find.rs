@@ -0,0 +1,88 @@
+extern crate ceptre;
+
+use ceptre::Context;
+
+fn context() -> Context {
+    Context::from_text(
+        "note 0 3 first . note 0 4 last\n\
+         note 1 3 first\n\
+         note-tmp 2 7 (a b)\n\
+         muted 1\n\
+         selected-instrument 0",
+    )
+    .unwrap()
+}
+
+#[test]
+fn find_binds_variables() {
+    let mut context = context();
+    let pattern = context.pattern("note INSTRUMENT POS first").unwrap();
+
+    let found = context
+        .find(&pattern)
+        .map(|b| (b.int("INSTRUMENT").unwrap(), b.int("POS").unwrap()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(found, vec![(0, 3), (1, 3)]);
+
+    context.append_state("note 2 5 first").unwrap();
+    assert_eq!(context.find(&pattern).count(), 3);
+}
+
+#[test]
+fn find_matches_any_length() {
+    let mut context = context();
+
+    let mut count = |text: &str| {
+        let pattern = context.pattern(text).unwrap();
+        context.find(&pattern).count()
+    };
+
+    assert_eq!(count("note I P"), 0);
+    assert_eq!(count("note I P D"), 3);
+    assert_eq!(count("selected-instrument I"), 1);
+
+    let pattern = context.pattern("note-tmp I P D").unwrap();
+    let b = context.find(&pattern).next().unwrap();
+    assert_eq!(
+        ceptre::term_to_string(b.get("D").unwrap(), &context.string_cache),
+        "(a b)"
+    );
+    assert_eq!(b.atom("D"), None);
+}
+
+#[test]
+fn find_joins_phrases_and_supports_negation() {
+    let mut context = context();
+
+    let pattern = context.pattern("note I P first . !muted I").unwrap();
+    let found = context
+        .find(&pattern)
+        .map(|b| b.int("I").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![0]);
+
+    let pattern = context
+        .pattern("note I P1 first . note I P2 last . < P1 P2")
+        .unwrap();
+    let found = context
+        .find(&pattern)
+        .map(|b| (b.int("P1").unwrap(), b.int("P2").unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![(3, 4)]);
+
+    let pattern = context.pattern("!note-tmp I P D").unwrap();
+    assert_eq!(context.find(&pattern).count(), 0);
+
+    let pattern = context.pattern("!note-tmp 5 P D").unwrap();
+    assert_eq!(context.find(&pattern).count(), 1);
+}
+
+#[test]
+fn pattern_reports_parse_errors() {
+    let mut context = context();
+
+    assert!(context.pattern("note I P D = x").is_err());
+    assert!(context.pattern("$note I").is_err());
+    assert!(context.pattern("").is_err());
+}
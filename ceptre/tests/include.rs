@@ -0,0 +1,105 @@
+extern crate ceptre;
+
+use ceptre::{Context, ParseError, Phrase};
+
+use std::io;
+
+fn from_files(paths: &[&str], files: &[(&str, &str)]) -> Result<Context, ParseError> {
+    Context::from_files_with_reader(paths, |path: &str| {
+        files
+            .iter()
+            .find(|&&(name, _)| name == path)
+            .map(|&(_, text)| text.to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+    })
+}
+
+#[test]
+fn includes_merge_files() {
+    let files = [
+        (
+            "game/main.ceptre",
+            "start\n#include \"levels/one.ceptre\"\n#include \"common.ceptre\"\nstart = go",
+        ),
+        (
+            "game/levels/one.ceptre",
+            "#include \"../common.ceptre\"\n\ngo = one",
+        ),
+        (
+            "game/common.ceptre",
+            "count 0\n\n#stage:\n  one . count N . + N 1 N2 = count N2\n  () =",
+        ),
+    ];
+
+    let mut context = from_files(&["game/main.ceptre"], &files).unwrap();
+
+    let rules = context
+        .rules()
+        .iter()
+        .map(|r| (r.id, r.span.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rules,
+        vec![
+            (2, "game/common.ceptre:4:3 in #stage".to_string()),
+            (3, "game/common.ceptre:5:3 in #stage".to_string()),
+            (4, "game/levels/one.ceptre:3:1".to_string()),
+            (5, "game/main.ceptre:4:1".to_string()),
+        ]
+    );
+
+    context.append_state("#stage").unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    let mut state = context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect::<Vec<_>>();
+    state.sort();
+    assert_eq!(state, vec!["count 1"]);
+}
+
+#[test]
+fn include_errors_name_the_file() {
+    let files = [
+        ("a.ceptre", "a\n#include \"b.ceptre\""),
+        ("b.ceptre", "\n#include \"./a.ceptre\""),
+        ("bad.ceptre", "#include \"missing.ceptre\"\nx = (y"),
+        ("syntax.ceptre", "x = (y"),
+        ("label.ceptre", "#stage:\n  #include \"syntax.ceptre\""),
+    ];
+
+    let e = from_files(&["a.ceptre"], &files).err().unwrap();
+    assert_eq!(e.file.as_deref(), Some("b.ceptre"));
+    assert_eq!((e.line, e.column), (2, 1));
+    assert_eq!(e.reason, "include cycle: a.ceptre -> b.ceptre -> a.ceptre");
+
+    let e = from_files(&["bad.ceptre"], &files).err().unwrap();
+    assert_eq!(e.file.as_deref(), Some("bad.ceptre"));
+    assert_eq!(e.reason, "can't read `missing.ceptre`: not found");
+
+    let e = from_files(&["a.ceptre", "syntax.ceptre"], &files)
+        .err()
+        .unwrap();
+    assert_eq!(e.file.as_deref(), Some("b.ceptre"));
+
+    let e = from_files(&["syntax.ceptre"], &files).err().unwrap();
+    assert_eq!(e.file.as_deref(), Some("syntax.ceptre"));
+    assert_eq!(e.reason, "unclosed `(`");
+
+    let e = from_files(&["label.ceptre"], &files).err().unwrap();
+    assert_eq!(e.reason, "`#include` isn't allowed in a `#stage:` block");
+
+    let e = Context::from_text("#include \"a.ceptre\"").err().unwrap();
+    assert_eq!(
+        e.reason,
+        "`#include` needs a program loaded with `Context::from_files`"
+    );
+
+    let e = Context::from_text("#include a.ceptre").err().unwrap();
+    assert_eq!(e.reason, "expected a quoted path after `#include`");
+
+    let e = Context::from_text("a \"b\" = c").err().unwrap();
+    assert_eq!(e.reason, "unexpected `\"b\"`");
+}
@@ -0,0 +1,74 @@
+extern crate ceptre;
+
+use ceptre::{Context, LintKind};
+
+fn lints(text: &str, external: &[&str]) -> Vec<(LintKind, String)> {
+    let context = Context::from_named_text("main.ceptre", text).unwrap();
+
+    context
+        .lint(external)
+        .iter()
+        .map(|lint| (lint.kind, lint.to_string()))
+        .collect()
+}
+
+#[test]
+fn lint_reports_mistakes() {
+    let text = "count 0\n\
+                bump . count N = count N2\n\
+                #clear I:\n  note I =\n  () = done\n\
+                \n\
+                note-tmp I P . !note-tpm I P = note I P\n\
+                #dummy . count N = count";
+
+    assert_eq!(
+        lints(text, &["note-tmp", "#clear"]),
+        vec![
+            (
+                LintKind::NeverProduced,
+                "main.ceptre:2:1: `bump` is consumed but never produced".to_string()
+            ),
+            (
+                LintKind::UnboundVariable,
+                "main.ceptre:2:18: `N2` isn't bound by any input".to_string()
+            ),
+            (
+                LintKind::NeverConsumed,
+                "main.ceptre:5:8 in #clear I: `done` is produced but never consumed".to_string()
+            ),
+            (
+                LintKind::NeverProduced,
+                "main.ceptre:7:16: `note-tpm` is consumed but never produced".to_string()
+            ),
+            (
+                LintKind::ArityMismatch,
+                "main.ceptre:7:32: `note` has 2 arguments here, \
+                 but 1 argument at main.ceptre:4:3 in #clear I"
+                    .to_string()
+            ),
+            (
+                LintKind::UnreachableStage,
+                "main.ceptre:8:1: `#dummy` is never produced, so rules that need it can't fire"
+                    .to_string()
+            ),
+            (
+                LintKind::ArityMismatch,
+                "main.ceptre:8:20: `count` has 0 arguments here, \
+                 but 1 argument in the initial state"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn lint_follows_phrases_through_variables() {
+    // `#dummy` and `(#next X)` become phrases through RETURN
+    let text = "#start . count N . + N 1 N2 = #clear 0 #dummy . #clear 1 (#next N2) . count N2\n\
+                #clear I RETURN:\n  () = RETURN\n\
+                \n\
+                #dummy =\n\
+                #next X . $count Y =";
+
+    assert_eq!(lints(text, &["#start", "count"]), vec![]);
+}
@@ -0,0 +1,58 @@
+extern crate ceptre;
+
+use ceptre::{Context, Phrase};
+
+fn run(program: &str) -> Vec<String> {
+    let mut context = Context::from_text_with_seed(program, 0).unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    let mut state = context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect::<Vec<_>>();
+    state.sort();
+    state
+}
+
+#[test]
+fn nested_lists_match_element_by_element() {
+    let state = run("place (x (o (x))) 0\n\
+                     place (x NOTES) P . + P 1 P2 = note P . place NOTES P2\n\
+                     place (o NOTES) P . + P 1 P2 = place NOTES P2\n\
+                     place (x) P = note P last");
+
+    assert_eq!(state, vec!["note 0", "note 2 last"]);
+}
+
+#[test]
+fn cons_patterns_split_a_list() {
+    let state = run("walk (a (b c) d)\n\
+                     walk (H | T) = seen H . walk T\n\
+                     walk () = done");
+
+    assert_eq!(state, vec!["done", "seen (b c)", "seen a", "seen d"]);
+
+    let state = run("go\n\
+                     go . == L (a b) = list (x y | L)");
+
+    assert_eq!(state, vec!["list (x y a b)"]);
+}
+
+#[test]
+fn length_and_append() {
+    let state = run("go (a b c)\n\
+                     go L . length L N . append L (d) L2 . append A (c) L = n N . l L2 . a A");
+
+    assert_eq!(state, vec!["a (a b)", "l (a b c d)", "n 3"]);
+
+    assert_eq!(run("go\ngo . length a N = n N"), vec!["go"]);
+    assert_eq!(run("go\ngo . append (a) B (b c) = b B"), vec!["go"]);
+}
+
+#[test]
+fn lists_are_not_atoms() {
+    assert_eq!(run("a (x)\na x = matched"), vec!["a (x)"]);
+    assert_eq!(run("a (x)\na (x) = matched"), vec!["matched"]);
+    assert_eq!(run("a ()\na () = matched"), vec!["matched"]);
+}
@@ -0,0 +1,217 @@
+extern crate ceptre;
+
+use ceptre::{Context, Matcher, Phrase};
+
+fn phrase_string(context: &Context, phrase: &Phrase) -> String {
+    ceptre::phrase_to_string(phrase, &context.string_cache)
+}
+
+fn state_strings(context: &Context) -> Vec<String> {
+    context
+        .state
+        .iter()
+        .map(|p| phrase_string(context, p))
+        .collect()
+}
+
+// Appends each batch of facts in turn and runs update, returning the state and
+// the side predicates evaluated after each update.
+fn run(program: &str, batches: &[&[&str]], seed: u64, matcher: Matcher) -> Vec<Vec<String>> {
+    let mut context = Context::from_text_with_seed(program, seed).unwrap();
+    context.set_matcher(matcher);
+
+    let mut result = vec![];
+
+    for batch in batches.iter() {
+        for fact in batch.iter() {
+            context.append_state(fact).unwrap();
+        }
+
+        let mut side_preds = vec![];
+        ceptre::update(&mut context, |p: &Phrase| {
+            side_preds.push(p.clone());
+            None
+        });
+
+        result.push(
+            side_preds
+                .iter()
+                .map(|p| phrase_string(&context, p))
+                .collect(),
+        );
+        result.push(state_strings(&context));
+    }
+
+    result
+}
+
+fn assert_same_results(program: &str, batches: &[&[&str]], seeds: u64) {
+    for seed in 0..seeds {
+        assert_eq!(
+            run(program, batches, seed, Matcher::Indexed),
+            run(program, batches, seed, Matcher::Incremental),
+            "seed {}, program:\n{}",
+            seed,
+            program
+        );
+    }
+}
+
+#[test]
+fn main_program() {
+    let setup: &[&str] = &[
+        "current-beat 0",
+        "selected-instrument 0",
+        "level-instruments 0 (x (o (x (x))))",
+        "level-instruments 1 (o (x (o (x))))",
+        "level-instrument-count 2",
+    ];
+
+    assert_same_results(
+        include_str!("main.ceptre"),
+        &[
+            setup,
+            &["#input-place 3"],
+            &["#set-beat 3"],
+            &["#set-beat 4"],
+            &["#input-change-right"],
+            &["#input-place 4"],
+            &["#set-beat 5"],
+            &["#input-place 14"],
+            &["#set-beat 6"],
+        ],
+        20,
+    );
+}
+
+#[test]
+fn side_predicates() {
+    assert_same_results(
+        "#ping:
+  token X . $target Y = ^hit X Y
+  () =
+
+token 1 . token 2 . token 3 . target a . target b",
+        &[&["#ping"], &["token 4", "#ping"]],
+        20,
+    );
+}
+
+// A small linear congruential generator, so that the generated programs don't
+// depend on the engine's random number generator.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+}
+
+const PREDICATES: [(&str, usize); 4] = [("p", 1), ("q", 2), ("r", 2), ("s", 1)];
+const VARIABLES: [&str; 3] = ["X", "Y", "Z"];
+const VALUES: [&str; 3] = ["a", "b", "c"];
+
+fn random_phrase(lcg: &mut Lcg, variables: &[&str]) -> String {
+    let (name, arity) = PREDICATES[lcg.next(PREDICATES.len())];
+    let mut phrase = name.to_string();
+
+    for _ in 0..arity {
+        phrase.push(' ');
+
+        if !variables.is_empty() && lcg.next(3) > 0 {
+            phrase.push_str(variables[lcg.next(variables.len())]);
+        } else {
+            phrase.push_str(VALUES[lcg.next(VALUES.len())]);
+        }
+    }
+
+    phrase
+}
+
+// Generates rules that join up to three inputs on shared variables. Each rule
+// consumes more phrases than it produces, so that update always terminates.
+fn random_program(lcg: &mut Lcg) -> String {
+    let mut lines = vec![];
+
+    for _ in 0..1 + lcg.next(5) {
+        let input_count = 1 + lcg.next(3);
+        let mut inputs = vec![];
+        let mut consumed = 0;
+
+        for i in 0..input_count {
+            let phrase = random_phrase(lcg, &VARIABLES);
+
+            if i > 0 && lcg.next(3) == 0 {
+                inputs.push(format!("${}", phrase));
+            } else {
+                inputs.push(phrase);
+                consumed += 1;
+            }
+        }
+
+        if lcg.next(3) == 0 {
+            inputs.push(format!("!{}", random_phrase(lcg, &VARIABLES)));
+        }
+
+        // only use variables bound by the forward inputs in outputs
+        let bound = VARIABLES
+            .iter()
+            .cloned()
+            .filter(|v| {
+                inputs
+                    .iter()
+                    .filter(|i| !i.starts_with('!'))
+                    .any(|i| i.split(' ').any(|t| t == *v))
+            })
+            .collect::<Vec<_>>();
+
+        let outputs = (0..lcg.next(consumed))
+            .map(|_| random_phrase(lcg, &bound))
+            .collect::<Vec<_>>();
+
+        lines.push(format!("{} = {}", inputs.join(" . "), outputs.join(" . ")));
+    }
+
+    lines.join("\n")
+}
+
+fn random_facts(lcg: &mut Lcg) -> Vec<String> {
+    (0..lcg.next(12)).map(|_| random_phrase(lcg, &[])).collect()
+}
+
+#[test]
+fn random_programs() {
+    let mut lcg = Lcg(1);
+
+    for _ in 0..200 {
+        let program = random_program(&mut lcg);
+        let batches = (0..3).map(|_| random_facts(&mut lcg)).collect::<Vec<_>>();
+        let batches = batches
+            .iter()
+            .map(|b| b.iter().map(|f| f.as_str()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let batches = batches.iter().map(|b| &b[..]).collect::<Vec<_>>();
+
+        assert_same_results(&program, &batches, 5);
+    }
+}
+
+#[test]
+fn switching_matcher_keeps_state() {
+    let program = "#tick . count N . + N 1 N2 = count N2\n\ncount 0";
+
+    let mut context = Context::from_text_with_seed(program, 3).unwrap();
+    context.append_state("#tick").unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    context.set_matcher(Matcher::Incremental);
+    context.append_state("#tick").unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    assert_eq!(context.matcher(), Matcher::Incremental);
+    assert_eq!(state_strings(&context), vec!["count 2"]);
+}
@@ -0,0 +1,326 @@
+extern crate ceptre;
+
+use ceptre::{Context, ParseError};
+
+fn parse_error(text: &str) -> ParseError {
+    Context::from_text(text).err().expect("parse error")
+}
+
+#[test]
+fn parse_error_reports_stray_dot() {
+    let e = parse_error("a 1\n\nfoo . . bar = baz");
+
+    assert_eq!(e.line, 3);
+    assert_eq!(e.column, 7);
+    assert_eq!(e.text, ".");
+    assert_eq!(e.reason, "unexpected `.`");
+}
+
+#[test]
+fn parse_error_reports_empty_phrase() {
+    let e = parse_error("foo . bar .\n\nbaz");
+
+    assert_eq!(e.line, 2);
+    assert_eq!(e.reason, "empty phrase");
+}
+
+#[test]
+fn parse_error_reports_missing_atom() {
+    let e = parse_error("foo . $ = bar");
+
+    assert_eq!(e.line, 1);
+    assert_eq!(e.column, 7);
+    assert_eq!(e.reason, "expected an atom after `$`");
+}
+
+#[test]
+fn parse_error_reports_negated_missing_atom() {
+    let e = parse_error("$! = a");
+
+    assert_eq!(e.line, 1);
+    assert_eq!(e.column, 1);
+    assert_eq!(e.reason, "expected an atom after `$!`");
+
+    assert_eq!(
+        parse_error("a . $! = b").reason,
+        "expected an atom after `$!`"
+    );
+}
+
+#[test]
+fn parse_error_reports_unbalanced_parentheses() {
+    let e = parse_error("#stage:\n  foo (a (b) = bar");
+
+    assert_eq!(e.line, 2);
+    assert_eq!(e.column, 7);
+    assert_eq!(e.reason, "unclosed `(`");
+}
+
+#[test]
+fn parse_error_reports_bad_cons() {
+    assert_eq!(
+        parse_error("a (| T) = b").reason,
+        "expected a term before `|`"
+    );
+    assert_eq!(
+        parse_error("a (H |) = b").reason,
+        "expected a term after `|`"
+    );
+    assert_eq!(
+        parse_error("a (H | T U) = b").reason,
+        "expected `)` after the tail of a list"
+    );
+}
+
+#[test]
+fn append_state_reports_errors() {
+    let mut context = Context::from_text("").unwrap();
+
+    assert!(context.append_state("foo !").is_err());
+    assert!(context.state.is_empty());
+}
+
+#[test]
+fn append_state_rejects_quiescence() {
+    let mut context = Context::from_text("a\na = b").unwrap();
+
+    for text in ["()", "qui"].iter() {
+        let e = context.append_state(text).unwrap_err();
+        assert_eq!(
+            e.reason,
+            "the quiescence marker can't be added to the state"
+        );
+    }
+    assert!(context.append_phrase(vec![]).is_err());
+
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+    assert_eq!(context.state.len(), 1);
+}
+
+fn rule_strings(context: &Context) -> Vec<String> {
+    context
+        .rules()
+        .iter()
+        .map(|r| {
+            let s = ceptre::rule_to_string(r, &context.string_cache);
+            let s = &s[..s.rfind("  // ").expect("span")];
+            s[s.find(": ").expect("id") + 2..].trim().to_string()
+        })
+        .collect()
+}
+
+fn assert_round_trip(text: &str) {
+    let context = Context::from_text(text).unwrap();
+
+    for rule in rule_strings(&context) {
+        let reparsed = Context::from_text(&rule).unwrap();
+        assert_eq!(rule_strings(&reparsed), vec![rule]);
+    }
+}
+
+#[test]
+fn round_trip_phrases() {
+    assert_round_trip("a = b");
+    assert_round_trip("a b c . d = e f");
+    assert_round_trip("#dummy =");
+    assert_round_trip("() = #done");
+    assert_round_trip("list (x (o (o (x)))) = list ((a b) c)");
+    assert_round_trip("list (H | T) . list () = list (T | H)");
+    assert_round_trip("foo X . !bar X Y = ^baz X");
+    assert_round_trip("count N . + N 1 N2 . %% N2 16 N3 . < N3 4 = count N3");
+}
+
+#[test]
+fn round_trip_labels() {
+    assert_round_trip(
+        "#clear INSTRUMENT RETURN:\n\
+         \x20 note INSTRUMENT POS DESC =\n\
+         \x20 () = RETURN\n\
+         \n\
+         $selected I . #input-place POS = #clear I (#placed POS)",
+    );
+}
+
+#[test]
+fn parse_ignores_whitespace_and_comments() {
+    let compact = Context::from_text(
+        "#stage X:\n\
+         a X . $b (c X) = d X\n\
+         () = done",
+    )
+    .unwrap();
+
+    let spaced = Context::from_text(
+        "// a stage with two rules\n\
+         #stage   X :\n\
+         \ta    X\n\
+         \x20 . $b ( c\n\
+         \x20      X )   // trailing comment\n\
+         \x20 =\td X\n\
+         // comments don't end a label block\n\
+         \x20 ()\n\
+         \x20   = done",
+    )
+    .unwrap();
+
+    assert_eq!(rule_strings(&compact), rule_strings(&spaced));
+}
+
+#[test]
+fn parse_atoms_containing_separators() {
+    let context = Context::from_text("a.b c=d . e= = f.").unwrap();
+
+    assert_eq!(rule_strings(&context), vec!["a.b c=d . e= = f."]);
+}
+
+#[test]
+fn parse_block_comments() {
+    let plain = Context::from_text(
+        "#stage X:\n\
+         a X = b X\n\
+         () =",
+    )
+    .unwrap();
+
+    let commented = Context::from_text(
+        "/* a stage\n\
+         \n\
+         with a blank line in its comment */\n\
+         #stage X: /* label */\n\
+         a X /* input */ = b X // output\n\
+         /*\n\
+         \n\
+         */\n\
+         () =",
+    )
+    .unwrap();
+
+    assert_eq!(rule_strings(&plain), rule_strings(&commented));
+    assert!(commented.state.is_empty());
+}
+
+#[test]
+fn parse_comments_directly_after_atoms() {
+    let plain = Context::from_text(
+        "a/b X = c X
+d . e",
+    )
+    .unwrap();
+    let commented = Context::from_text(
+        "a/b X = c X// trailing
+d/* x */. e/**/",
+    )
+    .unwrap();
+
+    assert_eq!(rule_strings(&plain), rule_strings(&commented));
+    let state = commented
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &commented.string_cache))
+        .collect::<Vec<_>>();
+    assert_eq!(state, vec!["d", "e"]);
+}
+
+#[test]
+fn parse_error_reports_unterminated_block_comment() {
+    let e = parse_error("a = b\n  /* c = d\n");
+
+    assert_eq!(e.line, 2);
+    assert_eq!(e.column, 3);
+    assert_eq!(e.reason, "unterminated block comment");
+}
+
+#[test]
+fn parse_annotations() {
+    let context = Context::from_text(
+        "@priority 2 a = b\n\
+         \n\
+         #stage:\n\
+         \x20 @priority -1\n\
+         \x20 a = c\n\
+         \x20 () =",
+    )
+    .unwrap();
+
+    let priorities = context
+        .rules()
+        .iter()
+        .map(|r| r.priority)
+        .collect::<Vec<_>>();
+    assert_eq!(priorities, vec![2, -1, 0]);
+
+    assert_round_trip("@priority 2 a = b");
+    assert_round_trip("@weight 3 a = b");
+    assert_round_trip("@weight 3\n@priority -1\na = b");
+}
+
+#[test]
+fn parse_error_reports_bad_annotations() {
+    assert_eq!(parse_error("@volume 2 a = b").reason, "unknown annotation");
+    assert_eq!(
+        parse_error("@priority high a = b").reason,
+        "expected an integer priority"
+    );
+    assert_eq!(
+        parse_error("@weight 0 a = b").reason,
+        "expected a positive integer weight"
+    );
+    assert_eq!(
+        parse_error("@weight 1.5 a = b").reason,
+        "expected a positive integer weight"
+    );
+    assert_eq!(
+        parse_error("@priority 1 a . b").reason,
+        "annotations are only allowed on rules"
+    );
+    assert_eq!(
+        parse_error("@priority 1 #stage:\n  a = b").reason,
+        "annotations are only allowed on rules"
+    );
+    assert_eq!(
+        parse_error("@priority 1\n\na = b").reason,
+        "expected a rule after annotation"
+    );
+}
+
+#[test]
+fn rules_keep_spans() {
+    let context = Context::from_named_text(
+        "test.ceptre",
+        "start\n\
+         \n\
+         #stage X:\n\
+         \x20 a X .\n\
+         \x20   $b = c\n\
+         \n\
+         @priority 1\n\
+         d = e",
+    )
+    .unwrap();
+
+    let rules = context.rules();
+    let staged = &rules[0];
+    assert_eq!(staged.span.to_string(), "test.ceptre:4:3 in #stage X");
+
+    let spans =
+        |spans: &[ceptre::Span]| spans.iter().map(|s| (s.line, s.column)).collect::<Vec<_>>();
+    assert_eq!(spans(&staged.input_spans), vec![(3, 1), (4, 3), (5, 5)]);
+    assert_eq!(spans(&staged.output_spans), vec![(5, 10), (3, 1), (5, 5)]);
+
+    let annotated = &rules[1];
+    assert_eq!(annotated.span.to_string(), "test.ceptre:8:1");
+    assert_eq!(
+        ceptre::rule_to_string(annotated, &context.string_cache),
+        "    2: @priority 1 d = e  // test.ceptre:8:1"
+    );
+}
+
+#[test]
+fn parse_error_reports_file() {
+    let e = Context::from_named_text("test.ceptre", "a = b\nc . . d")
+        .err()
+        .expect("parse error");
+
+    assert_eq!(e.file, Some("test.ceptre".to_string()));
+    assert_eq!(e.to_string(), "test.ceptre:2:5: unexpected `.`: `.`");
+}
@@ -0,0 +1,64 @@
+extern crate ceptre;
+
+use ceptre::{Context, Phrase, RuleOrder};
+
+// The second rule has the higher priority, so source order and priority order
+// disagree about which rule fires.
+const PROGRAM: &str = "go = first\n@priority 1\ngo = second";
+
+fn fire(seed: u64, rule_order: RuleOrder) -> String {
+    let mut context = Context::from_text_with_seed(PROGRAM, seed).unwrap();
+    context.set_rule_order(rule_order);
+    context.append_state("go").unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    assert_eq!(context.state.len(), 1);
+    ceptre::term_to_string(&context.state[0][0], &context.string_cache)
+}
+
+fn fired(rule_order: RuleOrder) -> Vec<String> {
+    let mut results = (0..20)
+        .map(|seed| fire(seed, rule_order))
+        .collect::<Vec<_>>();
+    results.sort();
+    results.dedup();
+    results
+}
+
+#[test]
+fn priority_order_is_the_default() {
+    let context = Context::from_text(PROGRAM).unwrap();
+    assert_eq!(context.rule_order(), RuleOrder::Priority);
+}
+
+#[test]
+fn priority_order_fires_the_highest_priority_rule() {
+    assert_eq!(fired(RuleOrder::Priority), vec!["second"]);
+}
+
+#[test]
+fn source_order_fires_the_first_rule() {
+    assert_eq!(fired(RuleOrder::Source), vec!["first"]);
+}
+
+#[test]
+fn random_order_ignores_priority() {
+    assert_eq!(fired(RuleOrder::Random), vec!["first", "second"]);
+}
+
+#[test]
+fn priority_order_shuffles_equal_priorities() {
+    let program = "@priority 1 go = a\n@priority 1 go = b\ngo = c";
+    let mut results = (0..20)
+        .map(|seed| {
+            let mut context = Context::from_text_with_seed(program, seed).unwrap();
+            context.append_state("go").unwrap();
+            ceptre::update(&mut context, |_: &Phrase| None);
+            ceptre::term_to_string(&context.state[0][0], &context.string_cache)
+        })
+        .collect::<Vec<_>>();
+    results.sort();
+    results.dedup();
+
+    assert_eq!(results, vec!["a", "b"]);
+}
@@ -0,0 +1,84 @@
+extern crate ceptre;
+
+use ceptre::{Bindings, Context, FromPhrase, FromPhraseError, QueryError, StringCache};
+
+#[derive(Debug, PartialEq)]
+struct Note {
+    instrument: u32,
+    pos: i32,
+    desc: String,
+}
+
+impl FromPhrase for Note {
+    fn pattern() -> String {
+        "note INSTRUMENT POS DESC".to_string()
+    }
+
+    fn from_bindings(
+        bindings: &Bindings,
+        string_cache: &StringCache,
+    ) -> Result<Note, FromPhraseError> {
+        Ok(Note {
+            instrument: bindings.value("INSTRUMENT", string_cache)?,
+            pos: bindings.value("POS", string_cache)?,
+            desc: bindings.value("DESC", string_cache)?,
+        })
+    }
+}
+
+fn note(instrument: u32, pos: i32, desc: &str) -> Note {
+    Note {
+        instrument,
+        pos,
+        desc: desc.to_string(),
+    }
+}
+
+#[test]
+fn query_converts_bindings() {
+    let mut context =
+        Context::from_text("note 0 3 first . note 1 -4 last . note-tmp 2 7 first").unwrap();
+
+    let notes = context.query::<Note>().unwrap().collect::<Vec<_>>();
+    assert_eq!(notes, vec![note(0, 3, "first"), note(1, -4, "last")]);
+
+    // the second query reuses the pattern parsed by the first
+    context.append_state("note 2 0 first").unwrap();
+    let notes = context.query::<Note>().unwrap().collect::<Vec<_>>();
+    assert_eq!(notes.len(), 3);
+}
+
+#[test]
+fn query_reports_conversion_errors() {
+    let error = |text: &str| match Context::from_text(text).unwrap().query::<Note>() {
+        Err(QueryError::FromPhrase(e)) => e.to_string(),
+        _ => panic!("expected an error"),
+    };
+
+    assert_eq!(
+        error("note first 3 a"),
+        "INSTRUMENT: expected an integer: `first`"
+    );
+    assert_eq!(
+        error("note 1.5 3 a"),
+        "INSTRUMENT: expected an integer: `1.5`"
+    );
+    assert_eq!(
+        error("note -1 3 a"),
+        "INSTRUMENT: integer out of range: `-1`"
+    );
+    assert_eq!(error("note 0 3 (a b)"), "DESC: expected an atom: `(a b)`");
+}
+
+#[test]
+fn bindings_value_reports_unbound_variables() {
+    let mut context = Context::from_text("note 0 3 first").unwrap();
+    let pattern = context.pattern("note I P D").unwrap();
+    let bindings = context.find(&pattern).next().unwrap();
+
+    let e = bindings
+        .value::<i32>("X", &context.string_cache)
+        .unwrap_err();
+    assert_eq!(e.reason, "unbound variable");
+    assert_eq!(bindings.value::<f64>("P", &context.string_cache), Ok(3.0));
+}
@@ -0,0 +1,57 @@
+extern crate ceptre;
+
+use ceptre::{Context, Matcher, Phrase};
+
+use std::io;
+
+fn state_strings(context: &Context) -> Vec<String> {
+    let mut state = context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect::<Vec<_>>();
+    state.sort();
+    state
+}
+
+#[test]
+fn reload_rules_keeps_state() {
+    for &matcher in [Matcher::Indexed, Matcher::Incremental].iter() {
+        let mut text = "count 0\nbump . count N . + N 1 N2 = count N2";
+        let mut context =
+            Context::from_files_with_reader(&["main.ceptre"], |_: &str| Ok(text.to_string()))
+                .unwrap();
+        context.set_matcher(matcher);
+
+        context.append_state("bump").unwrap();
+        ceptre::update(&mut context, |_: &Phrase| None);
+        assert_eq!(state_strings(&context), vec!["count 1"]);
+
+        text = "count 100\nbump . count N . + N 10 N2 = count N2";
+        context
+            .reload_rules_with_reader(&["main.ceptre"], |_: &str| Ok(text.to_string()))
+            .unwrap();
+
+        context.append_state("bump").unwrap();
+        ceptre::update(&mut context, |_: &Phrase| None);
+        assert_eq!(state_strings(&context), vec!["count 11"]);
+
+        // errors leave the old rules running
+        let e = context
+            .reload_rules_with_reader(&["main.ceptre"], |_: &str| {
+                Ok("bump . count N = (".to_string())
+            })
+            .unwrap_err();
+        assert_eq!(e.file.as_deref(), Some("main.ceptre"));
+
+        assert!(context
+            .reload_rules_with_reader(&["main.ceptre"], |_: &str| {
+                Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+            })
+            .is_err());
+
+        context.append_state("bump").unwrap();
+        ceptre::update(&mut context, |_: &Phrase| None);
+        assert_eq!(state_strings(&context), vec!["count 21"]);
+    }
+}
@@ -0,0 +1,196 @@
+extern crate ceptre;
+
+use ceptre::{Context, LoadError, Phrase, PhraseBuilder, UpdateOutcome};
+
+const PROGRAM: &str = "count 0 . roll\n\
+                       roll . count N . < N 12 . + N 1 N2 = count N2 . a N . roll\n\
+                       roll . count N . < N 12 . + N 1 N2 = count N2 . b N . roll\n\
+                       () . !done L = done (x (o (x)))";
+
+fn state_strings(context: &Context) -> Vec<String> {
+    let mut state = context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect::<Vec<_>>();
+    state.sort();
+    state
+}
+
+fn assert_restores<S, L>(save: S, load: L)
+where
+    S: Fn(&Context) -> Vec<u8>,
+    L: Fn(&mut Context, &[u8]) -> Result<(), LoadError>,
+{
+    // stopping after 12 rules leaves the context quiescent
+    for (seed, &limit) in (0..10).zip([5, 12].iter().cycle()) {
+        let mut context = Context::from_text_with_seed(PROGRAM, seed).unwrap();
+        context.append_state("volume 0.5").unwrap();
+        let outcome = ceptre::update_with_limit(&mut context, limit, |_: &Phrase| None);
+        assert_eq!(outcome, UpdateOutcome::StepLimitReached);
+
+        let saved = save(&context);
+
+        let mut loaded = Context::from_text_with_seed(PROGRAM, seed + 100).unwrap();
+        load(&mut loaded, &saved).unwrap();
+        assert_eq!(state_strings(&loaded), state_strings(&context));
+
+        ceptre::update(&mut context, |_: &Phrase| None);
+        ceptre::update(&mut loaded, |_: &Phrase| None);
+        assert_eq!(state_strings(&loaded), state_strings(&context));
+    }
+}
+
+#[test]
+fn text_save_restores_context() {
+    assert_restores(
+        |context| context.save_text().unwrap().into_bytes(),
+        |context, bytes| context.load_text(std::str::from_utf8(bytes).unwrap()),
+    );
+
+    let mut context = Context::from_text_with_seed("a (b c) 1.0 . d", 0).unwrap();
+    let text = context.save_text().unwrap();
+    assert!(text.ends_with("\na (b c) 1.0\nd\n"), "{}", text);
+
+    // the text is a program
+    let parsed = Context::from_text(&text).unwrap();
+    assert_eq!(state_strings(&parsed), state_strings(&context));
+
+    context.load_text("// a comment\nx 1\n\ny 2").unwrap();
+    assert_eq!(state_strings(&context), vec!["x 1", "y 2"]);
+}
+
+#[test]
+fn text_save_refuses_phrases_read_as_statements() {
+    for text in ["type x", "#include x"].iter() {
+        let mut context = Context::from_text("a").unwrap();
+        let phrase = text
+            .split(' ')
+            .fold(PhraseBuilder::new(), |builder, atom| builder.atom(atom))
+            .build(&mut context.string_cache)
+            .unwrap();
+        context.append_phrase(phrase).unwrap();
+
+        assert!(context.save_text().is_err(), "{}", text);
+
+        // the binary format keeps them
+        let bytes = context.save_binary();
+        let mut loaded = Context::from_text("").unwrap();
+        loaded.load_binary(&bytes).unwrap();
+        assert_eq!(state_strings(&loaded), state_strings(&context));
+    }
+
+    let mut context = Context::from_text("").unwrap();
+    match context.load_text("a\ntype note pos:int") {
+        Err(LoadError::Parse(e)) => {
+            assert_eq!((e.line, e.column), (2, 1));
+            assert_eq!(e.reason, "a saved state can't contain type declarations");
+        }
+        result => panic!("expected a parse error, not {:?}", result),
+    }
+    assert!(context.load_text("#include \"a.ceptre\"").is_err());
+}
+
+#[test]
+fn binary_save_restores_context() {
+    assert_restores(
+        |context| context.save_binary(),
+        |context, bytes| context.load_binary(bytes),
+    );
+
+    // atoms that can't be written in a program
+    let mut context = Context::from_text("").unwrap();
+    let phrase = PhraseBuilder::new()
+        .atom("two words")
+        .float(f64::INFINITY)
+        .list(PhraseBuilder::new())
+        .build(&mut context.string_cache)
+        .unwrap();
+    context.append_phrase(phrase.clone()).unwrap();
+
+    let bytes = context.save_binary();
+    let mut loaded = Context::from_text("").unwrap();
+    loaded.load_binary(&bytes).unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("two words")
+        .float(f64::INFINITY)
+        .list(PhraseBuilder::new())
+        .build(&mut loaded.string_cache)
+        .unwrap();
+    assert!(loaded.retract(&phrase));
+}
+
+#[test]
+fn load_reports_errors() {
+    let mut context = Context::from_text("a").unwrap();
+
+    match context.load_text("b\nc = d") {
+        Err(LoadError::Parse(e)) => {
+            assert_eq!(e.line, 2);
+            assert_eq!(e.reason, "a saved state can't contain rules");
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+
+    match context.load_text("// rng 1 2 3\nb") {
+        Err(LoadError::Invalid(_)) => (),
+        r => panic!("unexpected {:?}", r),
+    }
+
+    let bytes = context.save_binary();
+    for len in 0..bytes.len() {
+        assert!(context.load_binary(&bytes[..len]).is_err());
+    }
+
+    assert_eq!(state_strings(&context), vec!["a"]);
+}
+
+#[test]
+fn load_checks_declared_types() {
+    let mut context = Context::from_text("type count n:int\ncount 0").unwrap();
+
+    match context.load_text("a\ncount x") {
+        Err(LoadError::Parse(e)) => {
+            assert_eq!((e.line, e.column), (2, 1));
+            assert_eq!(e.reason, "expected an int for `n` of `count`");
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+
+    match context.load_text("a\n()") {
+        Err(LoadError::Parse(e)) => {
+            assert_eq!(
+                e.reason,
+                "the quiescence marker can't be added to the state"
+            )
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+
+    let untyped = Context::from_text("count x").unwrap();
+    match context.load_binary(&untyped.save_binary()) {
+        Err(LoadError::Parse(e)) => assert_eq!(e.reason, "expected an int for `n` of `count`"),
+        r => panic!("unexpected {:?}", r),
+    }
+
+    assert_eq!(state_strings(&context), vec!["count 0"]);
+}
+
+#[test]
+fn binary_load_refuses_deeply_nested_lists() {
+    let mut context = Context::from_text("").unwrap();
+    let nested = (0..1000).fold(PhraseBuilder::new(), |list, _| {
+        PhraseBuilder::new().atom("x").list(list)
+    });
+    let phrase = nested.build(&mut context.string_cache).unwrap();
+    context.append_phrase(phrase).unwrap();
+
+    match Context::from_text("")
+        .unwrap()
+        .load_binary(&context.save_binary())
+    {
+        Err(LoadError::Invalid(reason)) => assert_eq!(reason, "lists nested too deeply"),
+        r => panic!("unexpected {:?}", r),
+    }
+}
@@ -0,0 +1,72 @@
+extern crate ceptre;
+
+use ceptre::Context;
+
+const PROGRAM: &str = "
+#flip:
+  coin = heads
+  coin = tails
+  () =
+
+coin . coin . coin . coin
+";
+
+fn state_strings(context: &Context) -> Vec<String> {
+    context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect()
+}
+
+fn run(context: &mut Context, updates: usize) -> Vec<Vec<String>> {
+    let mut result = vec![];
+
+    for _ in 0..updates {
+        context.append_state("coin").unwrap();
+        context.append_state("#flip").unwrap();
+        ceptre::update(context, |_: &ceptre::Phrase| None);
+        result.push(state_strings(context));
+    }
+
+    result
+}
+
+#[test]
+fn same_seed_produces_same_states() {
+    let mut context1 = Context::from_text_with_seed(PROGRAM, 42).unwrap();
+    let mut context2 = Context::from_text_with_seed(PROGRAM, 42).unwrap();
+
+    assert_eq!(run(&mut context1, 20), run(&mut context2, 20));
+}
+
+#[test]
+fn different_seeds_produce_different_states() {
+    let mut context1 = Context::from_text_with_seed(PROGRAM, 1).unwrap();
+    let mut context2 = Context::from_text_with_seed(PROGRAM, 2).unwrap();
+
+    assert_ne!(run(&mut context1, 20), run(&mut context2, 20));
+}
+
+#[test]
+fn reseed_restarts_sequence() {
+    let mut context1 = Context::from_text_with_seed(PROGRAM, 7).unwrap();
+    let mut context2 = Context::from_text_with_seed(PROGRAM, 8).unwrap();
+
+    context2.reseed(7);
+
+    assert_eq!(run(&mut context1, 20), run(&mut context2, 20));
+}
+
+#[test]
+fn seeded_states_are_stable_across_platforms() {
+    let mut context = Context::from_text_with_seed(PROGRAM, 42).unwrap();
+
+    let states = run(&mut context, 3);
+
+    // the seeded sequence must not depend on the target's pointer width
+    assert_eq!(
+        states[2],
+        vec!["tails", "tails", "heads", "heads", "heads", "tails", "tails"]
+    );
+}
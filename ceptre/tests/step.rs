@@ -0,0 +1,143 @@
+extern crate ceptre;
+
+use ceptre::{Context, Firing, Phrase};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PROGRAM: &str = "count 0\n\
+                       \n\
+                       // counts ticks\n\
+                       #tick . count N . + N 1 N2 = count N2";
+
+fn phrase_string(context: &Context, phrase: &Phrase) -> String {
+    ceptre::phrase_to_string(phrase, &context.string_cache)
+}
+
+#[test]
+fn step_fires_one_rule() {
+    let mut context = Context::from_text(PROGRAM).unwrap();
+    context.append_state("#tick").unwrap();
+    context.append_state("#tick").unwrap();
+
+    let firing = ceptre::step(&mut context, |_: &Phrase| None).expect("firing");
+
+    assert_eq!(firing.rule_id, 1);
+    assert_eq!((firing.span.line, firing.span.column), (4, 1));
+
+    let removed = firing
+        .removed
+        .iter()
+        .map(|p| phrase_string(&context, p))
+        .collect::<Vec<_>>();
+    let added = firing
+        .added
+        .iter()
+        .map(|p| phrase_string(&context, p))
+        .collect::<Vec<_>>();
+    assert_eq!(removed, vec!["#tick", "count 0"]);
+    assert_eq!(added, vec!["count 1"]);
+
+    let bindings = firing
+        .bindings
+        .iter()
+        .map(|&(var, ref value)| {
+            format!(
+                "{}={}",
+                context.string_cache.from_atom(var),
+                ceptre::term_to_string(value, &context.string_cache)
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(bindings, vec!["N=0", "N2=1"]);
+
+    assert_eq!(
+        ceptre::firing_to_string(&firing, &context.string_cache),
+        "    1: #tick . count 0 = count 1 [N = 0, N2 = 1]  // 4:1"
+    );
+
+    let pattern = context.pattern("#tick").unwrap();
+    assert!(context.find(&pattern).next().is_some());
+    assert!(ceptre::step(&mut context, |_: &Phrase| None).is_some());
+    assert!(ceptre::step(&mut context, |_: &Phrase| None).is_none());
+    let pattern = context.pattern("count 2").unwrap();
+    assert!(context.find(&pattern).next().is_some());
+}
+
+#[test]
+fn update_calls_trace_for_each_firing() {
+    let mut context = Context::from_text(PROGRAM).unwrap();
+
+    let firings: Rc<RefCell<Vec<Firing>>> = Rc::new(RefCell::new(vec![]));
+    let trace_firings = firings.clone();
+    context.set_trace(move |firing: &Firing, _: &ceptre::StringCache| {
+        trace_firings.borrow_mut().push(firing.clone());
+    });
+
+    for _ in 0..3 {
+        context.append_state("#tick").unwrap();
+    }
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    assert_eq!(firings.borrow().len(), 3);
+    assert!(firings.borrow().iter().all(|f| f.rule_id == 1));
+
+    context.clear_trace();
+    context.append_state("#tick").unwrap();
+    ceptre::update(&mut context, |_: &Phrase| None);
+
+    assert_eq!(firings.borrow().len(), 3);
+}
+
+#[test]
+fn enabled_rules_match_without_firing() {
+    let mut context = Context::from_text(
+        "count 0\n\
+         #tick . count N . + N 1 N2 = count N2\n\
+         #tick . count 5 = done\n\
+         () . count N = idle N",
+    )
+    .unwrap();
+
+    let enabled_ids = |context: &Context| {
+        context
+            .enabled_rules(|_: &Phrase| None)
+            .iter()
+            .map(|r| r.id)
+            .collect::<Vec<_>>()
+    };
+
+    // only the quiescence rule matches until there's a tick
+    assert_eq!(enabled_ids(&context), vec![3]);
+
+    context.append_state("#tick").unwrap();
+    assert_eq!(enabled_ids(&context), vec![1]);
+
+    let pattern = context.pattern("count N").unwrap();
+    context.retract_matching(&pattern);
+    context.append_state("count 5").unwrap();
+    assert_eq!(enabled_ids(&context), vec![1, 2]);
+    assert_eq!(context.state.len(), 2);
+
+    assert!(ceptre::step(&mut context, |_: &Phrase| None).is_some());
+}
+
+#[test]
+fn enabled_rules_never_call_side_outputs() {
+    let context = Context::from_text(
+        "go = ^ping
+^pong X . go = done X
+
+go",
+    )
+    .unwrap();
+
+    let mut calls = vec![];
+    let rules = context.enabled_rules(|p: &Phrase| {
+        calls.push(ceptre::phrase_to_string(p, &context.string_cache));
+        None
+    });
+
+    assert_eq!(rules.iter().map(|r| r.id).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(calls, vec!["^pong X"]);
+}
@@ -0,0 +1,135 @@
+extern crate ceptre;
+
+use ceptre::{ArgType, Context, PhraseBuilder};
+
+fn error(text: &str) -> (usize, usize, String, String) {
+    let e = Context::from_text(text).err().unwrap();
+    (e.line, e.column, e.text, e.reason)
+}
+
+#[test]
+fn declarations_are_checked_at_load() {
+    let context = Context::from_text(
+        "type note instrument:int pos:int desc:atom\n\
+         type volume v:float\n\
+         type seq s:list\n\
+         note 0 3 first . volume 1 . volume 0.5 . seq (x (o))\n\
+         seq (x | T) . note I P D = note I P last . seq T",
+    )
+    .unwrap();
+
+    let decl = context.declared_type("note").unwrap();
+    assert_eq!(decl.args[1], ("pos".to_string(), ArgType::Int));
+    assert_eq!((decl.span.line, decl.span.column), (1, 1));
+    assert!(context.declared_type("volume").is_some());
+    assert!(context.declared_type("go").is_none());
+
+    let declare = "type note instrument:int pos:int\n";
+
+    assert_eq!(
+        error(&format!("{}note 1 x", declare)),
+        (
+            2,
+            1,
+            "x".to_string(),
+            "expected an int for `pos` of `note`".to_string()
+        )
+    );
+    assert_eq!(
+        error(&format!("{}go = note 1", declare)),
+        (
+            2,
+            6,
+            "note 1".to_string(),
+            "`note` is declared with 2 arguments, not 1".to_string()
+        )
+    );
+    assert_eq!(
+        error(&format!("go . !note 1 2 3 =\n{}", declare)),
+        (
+            1,
+            6,
+            "!note 1 2 3".to_string(),
+            "`note` is declared with 2 arguments, not 3".to_string()
+        )
+    );
+    assert_eq!(
+        error(&format!("{}type note instrument:int", declare)).3,
+        "`note` is already declared at 1:1"
+    );
+    assert!(Context::from_text(&format!("{}{}", declare, declare)).is_ok());
+
+    assert_eq!(
+        error("type note pos:num").3,
+        "unknown type, expected `int`, `float`, `atom`, `list` or `any`"
+    );
+    assert_eq!(error("type note pos").3, "expected `name:type`");
+    assert_eq!(
+        error("type X pos:int").3,
+        "expected a predicate after `type`"
+    );
+    assert_eq!(
+        error("@priority 1 type note pos:int").3,
+        "annotations are only allowed on rules"
+    );
+    assert_eq!(
+        error("#stage:\n  type note pos:int").3,
+        "`type` isn't allowed in a `#stage:` block"
+    );
+}
+
+#[test]
+fn append_state_and_find_check_declarations() {
+    let mut context = Context::from_text("type note instrument:int pos:int desc:atom").unwrap();
+
+    context.append_state("note 0 3 first").unwrap();
+
+    let e = context.append_state("note 0 3").unwrap_err();
+    assert_eq!(e.reason, "`note` is declared with 3 arguments, not 2");
+
+    let e = context.append_state("note 0 (3) first").unwrap_err();
+    assert_eq!(
+        (e.text.as_str(), e.reason.as_str()),
+        ("(3)", "expected an int for `pos` of `note`")
+    );
+
+    let pattern = context.pattern("note I P D").unwrap();
+    assert_eq!(context.find(&pattern).count(), 1);
+
+    let e = context.pattern("note I P").unwrap_err();
+    assert_eq!(e.reason, "`note` is declared with 3 arguments, not 2");
+
+    assert_eq!(context.state.len(), 1);
+}
+
+#[test]
+fn append_phrase_checks_declarations() {
+    let mut context = Context::from_text("type #set-beat beat:int").unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("#set-beat")
+        .int(3)
+        .build(&mut context.string_cache)
+        .unwrap();
+    context.append_phrase(phrase).unwrap();
+
+    let phrase = PhraseBuilder::new()
+        .atom("#set-beat")
+        .atom("x")
+        .build(&mut context.string_cache)
+        .unwrap();
+    let e = context.append_phrase(phrase).unwrap_err();
+    assert_eq!(
+        (e.text.as_str(), e.reason.as_str()),
+        ("x", "expected an int for `beat` of `#set-beat`")
+    );
+
+    let phrase = PhraseBuilder::new()
+        .atom("#set-beat")
+        .build(&mut context.string_cache)
+        .unwrap();
+    let e = context.append_phrase(phrase).unwrap_err();
+    assert_eq!(e.reason, "`#set-beat` is declared with 1 arguments, not 0");
+
+    assert_eq!(context.state.len(), 1);
+}
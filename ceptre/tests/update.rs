@@ -0,0 +1,168 @@
+extern crate ceptre;
+
+#[test]
+fn update_runs_to_quiescence() {
+    let mut context = ceptre::Context::from_text(
+        "count 0\n\
+         \n\
+         #tick . count N . + N 1 N2 = count N2",
+    )
+    .unwrap();
+
+    context.append_state("#tick").unwrap();
+    context.append_state("#tick").unwrap();
+
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+
+    let pattern = context.pattern("count 2").unwrap();
+    assert!(context.find(&pattern).next().is_some());
+    let pattern = context.pattern("#tick").unwrap();
+    assert!(context.find(&pattern).next().is_none());
+}
+
+#[test]
+fn update_calls_side_input() {
+    let mut context = ceptre::Context::from_text("#ping = ^pong 1").unwrap();
+
+    context.append_state("#ping").unwrap();
+
+    let pong = context.to_atom("^pong");
+    let mut pong_count = 0;
+
+    ceptre::update(&mut context, |p: &ceptre::Phrase| {
+        if p[0].as_atom() == Some(pong) {
+            pong_count += 1;
+        }
+
+        None
+    });
+
+    assert_eq!(pong_count, 1);
+}
+
+fn state_strings(context: &ceptre::Context) -> Vec<String> {
+    context
+        .state
+        .iter()
+        .map(|p| ceptre::phrase_to_string(p, &context.string_cache))
+        .collect()
+}
+
+#[test]
+fn update_with_limit_reaches_quiescence() {
+    let mut context =
+        ceptre::Context::from_text("#tick . count N . + N 1 N2 = count N2\n\ncount 0").unwrap();
+
+    context.append_state("#tick").unwrap();
+
+    let outcome = ceptre::update_with_limit(&mut context, 1, |_: &ceptre::Phrase| None);
+
+    assert_eq!(outcome, ceptre::UpdateOutcome::Quiescent);
+    assert_eq!(state_strings(&context), vec!["count 1"]);
+}
+
+#[test]
+fn update_with_limit_stops_after_max_steps() {
+    let mut context =
+        ceptre::Context::from_text("count N . + N 1 N2 = count N2\n\ncount 0").unwrap();
+
+    let outcome = ceptre::update_with_limit(&mut context, 10, |_: &ceptre::Phrase| None);
+    assert_eq!(outcome, ceptre::UpdateOutcome::StepLimitReached);
+    assert_eq!(state_strings(&context), vec!["count 10"]);
+
+    let outcome = ceptre::update_with_limit(&mut context, 5, |_: &ceptre::Phrase| None);
+    assert_eq!(outcome, ceptre::UpdateOutcome::StepLimitReached);
+    assert_eq!(state_strings(&context), vec!["count 15"]);
+}
+
+#[test]
+fn update_with_limit_resumes_at_quiescence() {
+    let mut context = ceptre::Context::from_text("start . () = done\n\nstart").unwrap();
+
+    let outcome = ceptre::update_with_limit(&mut context, 0, |_: &ceptre::Phrase| None);
+    assert_eq!(outcome, ceptre::UpdateOutcome::StepLimitReached);
+    assert_eq!(state_strings(&context), vec!["start"]);
+
+    ceptre::update(&mut context, |_: &ceptre::Phrase| None);
+    assert_eq!(state_strings(&context), vec!["done"]);
+}
+
+#[test]
+fn update_with_limit_detects_cycles() {
+    let mut context = ceptre::Context::from_text(
+        "ping X = pong X\n\
+         pong X = ping X\n\
+         \n\
+         ping 1 . other",
+    )
+    .unwrap();
+
+    let outcome = ceptre::update_with_limit(&mut context, 100, |_: &ceptre::Phrase| None);
+
+    assert_eq!(
+        outcome,
+        ceptre::UpdateOutcome::CycleDetected {
+            rule_ids: vec![0, 1]
+        }
+    );
+    assert_eq!(state_strings(&context), vec!["other", "ping 1"]);
+}
+
+#[test]
+fn update_with_limit_skips_side_outputs_of_the_rule_it_stops_before() {
+    let mut context = ceptre::Context::from_text("a = b\nb = ^ping\n\na").unwrap();
+
+    let mut ping_count = 0;
+    let outcome = ceptre::update_with_limit(&mut context, 1, |_: &ceptre::Phrase| {
+        ping_count += 1;
+        None
+    });
+
+    assert_eq!(outcome, ceptre::UpdateOutcome::StepLimitReached);
+    assert_eq!(state_strings(&context), vec!["b"]);
+    assert_eq!(ping_count, 0);
+
+    ceptre::update(&mut context, |_: &ceptre::Phrase| {
+        ping_count += 1;
+        None
+    });
+
+    assert_eq!(ping_count, 1);
+}
+
+#[test]
+fn update_with_limit_compares_states_with_the_same_hash() {
+    // the sums of the phrase hashes of these two states are equal
+    let before = [
+        (1, 2),
+        (7, 3),
+        (9, 1),
+        (11, 6),
+        (12, 1),
+        (13, 5),
+        (14, 3),
+        (16, 2),
+    ];
+    let after = [(2, 5), (3, 6), (4, 4), (5, 1), (6, 8), (10, 7), (15, 3)];
+
+    let phrases = |counts: &[(i32, usize)]| {
+        counts
+            .iter()
+            .flat_map(|&(n, count)| vec![format!("n {}", n); count])
+            .collect::<Vec<_>>()
+            .join(" . ")
+    };
+
+    let mut context = ceptre::Context::from_text(&format!(
+        "{} = {}\n\n{}",
+        phrases(&before),
+        phrases(&after),
+        phrases(&before)
+    ))
+    .unwrap();
+
+    let outcome = ceptre::update_with_limit(&mut context, 100, |_: &ceptre::Phrase| None);
+
+    assert_eq!(outcome, ceptre::UpdateOutcome::Quiescent);
+    assert_eq!(state_strings(&context).len(), 34);
+}
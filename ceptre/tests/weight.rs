@@ -0,0 +1,76 @@
+extern crate ceptre;
+
+use ceptre::{Context, Phrase, RuleOrder};
+
+const RUNS: u64 = 4000;
+
+// Runs the program once for each seed and returns how often each rule fired,
+// as a fraction of the runs.
+fn frequencies(program: &str, rule_order: RuleOrder, outputs: &[&str]) -> Vec<f64> {
+    let mut counts = vec![0; outputs.len()];
+
+    for seed in 0..RUNS {
+        let mut context = Context::from_text_with_seed(program, seed).unwrap();
+        context.set_rule_order(rule_order);
+        context.append_state("go").unwrap();
+        ceptre::update(&mut context, |_: &Phrase| None);
+
+        let fired = ceptre::term_to_string(&context.state[0][0], &context.string_cache);
+        counts[outputs.iter().position(|&o| o == fired).expect("output")] += 1;
+    }
+
+    counts.into_iter().map(|c| c as f64 / RUNS as f64).collect()
+}
+
+fn assert_near(actual: &[f64], expected: &[f64]) {
+    for (a, e) in actual.iter().zip(expected.iter()) {
+        assert!(
+            (a - e).abs() < 0.03,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+}
+
+#[test]
+fn rules_fire_in_proportion_to_weight() {
+    let program = "go = a\n@weight 3 go = b\n@weight 6 go = c";
+
+    for &rule_order in [RuleOrder::Random, RuleOrder::Priority].iter() {
+        assert_near(
+            &frequencies(program, rule_order, &["a", "b", "c"]),
+            &[0.1, 0.3, 0.6],
+        );
+    }
+}
+
+#[test]
+fn rules_that_cannot_fire_are_ignored() {
+    let program = "go = a\n@weight 3 go = b\n@weight 100 stop = c";
+
+    assert_near(
+        &frequencies(program, RuleOrder::Random, &["a", "b"]),
+        &[0.25, 0.75],
+    );
+}
+
+#[test]
+fn priority_outranks_weight() {
+    let program = "@priority 1 go = a\n@priority 1 @weight 4 go = b\n@weight 100 go = c";
+
+    assert_near(
+        &frequencies(program, RuleOrder::Priority, &["a", "b", "c"]),
+        &[0.2, 0.8, 0.0],
+    );
+}
+
+#[test]
+fn source_order_ignores_weight() {
+    let program = "go = a\n@weight 100 go = b";
+
+    assert_eq!(
+        frequencies(program, RuleOrder::Source, &["a", "b"]),
+        vec![1.0, 0.0]
+    );
+}
@@ -1,33 +1,113 @@
-#[macro_use]
-extern crate lazy_static;
-extern crate rand;
+extern crate ceptre;
 extern crate raylib_rs;
-extern crate regex;
 
 #[macro_use]
 mod dump;
-mod ceptre;
 
+use ceptre::{FromPhrase, PhraseBuilder};
 use raylib_rs as ray;
 
 use std::cell::RefCell;
+use std::fs;
 use std::os::raw::{c_int, c_void};
+use std::path::Path;
 use std::ptr::null_mut;
-use std::str::FromStr;
+use std::time::SystemTime;
 use std::vec::Vec;
 
 const WIDTH: i32 = 800;
 const HEIGHT: i32 = 600;
 
+// the most rules that may fire in a frame, so that a rule loop can't hang the game.
+const MAX_RULE_STEPS: usize = 10_000;
+
+// the ceptre program, embedded so that the web build doesn't need to read files.
+// files included with `#include` must be listed here.
+const PROGRAM_FILES: &[(&str, &str)] = &[("main.ceptre", include_str!("main.ceptre"))];
+
+// phrases that the game appends or reads, which `ceptre::Context::lint`
+// shouldn't report as never produced or never consumed.
+const HOST_PREDICATES: &[&str] = &[
+    "#input-place",
+    "#input-change-left",
+    "#input-change-right",
+    "#set-beat",
+    "current-beat",
+    "selected-instrument",
+    "level-instruments",
+    "level-instrument-count",
+    "note",
+    "note-tmp",
+];
+
+// native builds reload the rules when the program files in this directory change.
+const PROGRAM_DIR: &str = "src";
+const RELOAD_INTERVAL: f64 = 0.5;
+
+// where S saves the level being composed and L restores it, in native builds.
+const SAVE_PATH: &str = "ld42-save.ceptre";
+
 struct State {
     time: f64,
     ceptre_context: ceptre::Context,
+    patterns: Patterns,
     error: ray::Sound,
     current_level: i32,
     levels: Vec<LevelSounds>,
     level_start_time: f64,
     level_complete_time: Option<f64>,
     collide_beat: Option<(i32, f64)>,
+    // when the program files were last changed, and when that was last checked
+    rules_modified: Option<SystemTime>,
+    rules_checked_time: f64,
+    // the error from reloading the rules, shown until they reload successfully
+    rules_error: Option<String>,
+}
+
+#[derive(FromPhrase)]
+#[allow(dead_code)]
+struct Note {
+    instrument: i32,
+    pos: i32,
+    desc: ceptre::Atom,
+}
+
+// a note that is being placed, which becomes a `note` if it doesn't collide.
+#[derive(FromPhrase)]
+#[ceptre(name = "note-tmp")]
+#[allow(dead_code)]
+struct TempNote {
+    instrument: i32,
+    pos: i32,
+    desc: ceptre::Atom,
+}
+
+#[derive(FromPhrase)]
+struct SelectedInstrument {
+    instrument: i32,
+}
+
+#[derive(FromPhrase)]
+struct CurrentLevel {
+    level: i32,
+}
+
+// the patterns that the game reads the state with, parsed once when the
+// program is loaded.
+struct Patterns {
+    level_instruments: ceptre::Pattern,
+    first_notes: ceptre::Pattern,
+}
+
+impl Patterns {
+    fn new(context: &mut ceptre::Context) -> Patterns {
+        let mut pattern = |text: &str| context.pattern(text).expect("pattern");
+
+        Patterns {
+            level_instruments: pattern("level-instruments INSTRUMENT SEQUENCE"),
+            first_notes: pattern("note INSTRUMENT POS first"),
+        }
+    }
 }
 
 impl State {
@@ -35,17 +115,30 @@ impl State {
         self.levels.get(level as usize)
     }
 
-    fn instruments(&self) -> Vec<&ceptre::Phrase> {
-        self.ceptre_context.find_phrases(Some("level-instruments"))
+    fn instrument_count(&self) -> i32 {
+        self.ceptre_context.find(&self.patterns.level_instruments).count() as i32
+    }
+
+    fn selected_instrument(&mut self) -> Option<i32> {
+        self.ceptre_context
+            .query::<SelectedInstrument>()
+            .expect("query")
+            .next()
+            .map(|s| s.instrument)
+    }
+
+    fn notes(&mut self) -> Vec<Note> {
+        self.ceptre_context
+            .query::<Note>()
+            .expect("query")
+            .collect()
     }
 
-    fn selected_instrument(&self) -> Option<i32> {
+    fn temp_notes(&mut self) -> Vec<TempNote> {
         self.ceptre_context
-            .find_phrase(Some("selected-instrument"))
-            .map(|p| {
-                i32::from_str(p[1].as_str(&self.ceptre_context.string_cache))
-                    .expect("selected_instrument")
-            })
+            .query::<TempNote>()
+            .expect("query")
+            .collect()
     }
 
     fn beat_pos_for_time(&self, level: i32, time: f64) -> f32 {
@@ -57,6 +150,11 @@ impl State {
         16.0 * frac
     }
 
+    fn append_phrase(&mut self, phrase: PhraseBuilder) {
+        let phrase = phrase.build(&mut self.ceptre_context.string_cache).expect("build");
+        self.ceptre_context.append_phrase(phrase).expect("append_phrase");
+    }
+
     fn reset_level(&mut self, level: i32) {
         self.current_level = level;
         self.level_start_time = self.time;
@@ -64,8 +162,13 @@ impl State {
 
         self.ceptre_context.state.clear();
 
-        self.ceptre_context.append_state("current-beat 0");
-        self.ceptre_context.append_state("selected-instrument 0");
+        self.append_phrase(PhraseBuilder::new().atom("current-level").int(level as i64));
+        self.ceptre_context
+            .append_state("current-beat 0")
+            .expect("append_state");
+        self.ceptre_context
+            .append_state("selected-instrument 0")
+            .expect("append_state");
 
         let instrument_count = &self
             .level_sounds(level)
@@ -74,7 +177,8 @@ impl State {
             .len();
 
         for i in 0..*instrument_count {
-            let mut sequence_str = "".to_string();
+            // a nested list from the first to the last x, (x (o (o (x)))).
+            let mut sequence: Option<PhraseBuilder> = None;
 
             for v in self.level_sounds(level).expect("level_sounds").instruments[i]
                 .sequence
@@ -82,15 +186,91 @@ impl State {
                 .rev()
                 .skip_while(|v| !*v)
             {
-                sequence_str = format!("({} {})", if *v { "x" } else { "o" }, sequence_str);
+                let mut item = PhraseBuilder::new().atom(if *v { "x" } else { "o" });
+                if let Some(rest) = sequence {
+                    item = item.list(rest);
+                }
+
+                sequence = Some(item);
+            }
+
+            let mut phrase = PhraseBuilder::new().atom("level-instruments").int(i as i64);
+            if let Some(sequence) = sequence {
+                phrase = phrase.list(sequence);
             }
 
-            self.ceptre_context
-                .append_state(&format!("level-instruments {} {}", i, sequence_str));
+            self.append_phrase(phrase);
         }
 
-        self.ceptre_context
-            .append_state(&format!("level-instrument-count {}", instrument_count));
+        self.append_phrase(
+            PhraseBuilder::new()
+                .atom("level-instrument-count")
+                .int(*instrument_count as i64),
+        );
+    }
+
+    fn reload_rules_if_changed(&mut self) {
+        if self.time - self.rules_checked_time < RELOAD_INTERVAL {
+            return;
+        }
+
+        self.rules_checked_time = self.time;
+
+        let modified = program_modified();
+        if modified.is_none() || modified == self.rules_modified {
+            return;
+        }
+
+        self.rules_modified = modified;
+
+        match self
+            .ceptre_context
+            .reload_rules(&[Path::new(PROGRAM_DIR).join("main.ceptre")])
+        {
+            Ok(()) => {
+                eprintln!("reloaded rules");
+                print_lints(&self.ceptre_context);
+                self.rules_error = None;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                self.rules_error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.ceptre_context.save(SAVE_PATH, ceptre::SaveFormat::Text) {
+            eprintln!("{}: {}", SAVE_PATH, e);
+        }
+    }
+
+    fn load(&mut self) {
+        if let Err(e) = self.ceptre_context.load(SAVE_PATH) {
+            eprintln!("{}: {}", SAVE_PATH, e);
+            return;
+        }
+
+        let level = self
+            .ceptre_context
+            .query::<CurrentLevel>()
+            .ok()
+            .and_then(|mut levels| levels.next())
+            .map(|l| l.level);
+
+        match level {
+            Some(level) if self.level_sounds(level).is_some() => {
+                self.current_level = level;
+                self.level_start_time = self.time;
+                self.level_complete_time = None;
+            }
+            _ => {
+                eprintln!("{}: no level to restore", SAVE_PATH);
+
+                let current_level = self.current_level;
+                self.reset_level(current_level);
+            }
+        }
     }
 }
 
@@ -118,15 +298,45 @@ fn main() {
     let error = ray::load_sound("assets/error.ogg");
     ray::set_sound_volume(error, 0.3);
 
+    let read_program_file = |path: &str| {
+        PROGRAM_FILES
+            .iter()
+            .find(|&&(name, _)| name == path)
+            .map(|&(_, text)| text.to_string())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "not in PROGRAM_FILES"))
+    };
+
+    let mut ceptre_context = match ceptre::Context::from_files_with_reader(&["main.ceptre"], read_program_file) {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    print_lints(&ceptre_context);
+
+    let patterns = Patterns::new(&mut ceptre_context);
+
+    if std::env::var("CEPTRE_TRACE").is_ok() {
+        ceptre_context.set_trace(|firing: &ceptre::Firing, string_cache: &ceptre::StringCache| {
+            eprintln!("{}", ceptre::firing_to_string(firing, string_cache));
+        });
+    }
+
     let mut state = State {
         time: ray::get_time(),
-        ceptre_context: ceptre::Context::from_text(include_str!("main.ceptre")),
+        ceptre_context,
+        patterns,
         error,
         current_level: 0,
         levels: create_levels(),
         level_start_time: ray::get_time(),
         level_complete_time: None,
         collide_beat: None,
+        rules_modified: program_modified(),
+        rules_checked_time: ray::get_time(),
+        rules_error: None,
     };
 
     let current_level = state.current_level;
@@ -224,6 +434,16 @@ fn update_draw_frame() {
     let dt = (time2 - time1) as f32;
     state.time = time2;
 
+    if !cfg!(target_os = "emscripten") {
+        state.reload_rules_if_changed();
+
+        if ray::is_key_pressed(ray::KEY_S) {
+            state.save();
+        } else if ray::is_key_pressed(ray::KEY_L) {
+            state.load();
+        }
+    }
+
     let current_level = state.current_level;
 
     if let Some(ref sounds) = state.level_sounds(current_level) {
@@ -234,7 +454,7 @@ fn update_draw_frame() {
         }
     }
 
-    let instrument_count = state.instruments().len() as i32;
+    let instrument_count = state.instrument_count();
 
     #[derive(Eq, PartialEq)]
     enum NoteType {
@@ -249,18 +469,12 @@ fn update_draw_frame() {
             v.push(NoteType::None);
         }
 
-        for note in state.ceptre_context.find_phrases(Some("note")).iter() {
-            let instrument = i32::from_str(note[1].as_str(&state.ceptre_context.string_cache))
-                .expect("instrument") as usize;
-
-            v[instrument] = NoteType::Normal;
+        for note in state.notes() {
+            v[note.instrument as usize] = NoteType::Normal;
         }
 
-        for note in state.ceptre_context.find_phrases(Some("note-tmp")).iter() {
-            let instrument = i32::from_str(note[1].as_str(&state.ceptre_context.string_cache))
-                .expect("instrument") as usize;
-
-            v[instrument] = NoteType::Temp;
+        for note in state.temp_notes() {
+            v[note.instrument as usize] = NoteType::Temp;
         }
 
         v
@@ -291,6 +505,14 @@ fn update_draw_frame() {
     let is_new_beat = is_new_beat
         || state.beat_pos_for_time(current_level, time1).floor() as i32 != beat_pos.floor() as i32;
 
+    // the instruments whose sequence starts on the current beat
+    let first_instruments = state
+        .ceptre_context
+        .find(&state.patterns.first_notes)
+        .filter(|b| b.int("POS") == Some(beat_pos.floor() as i64))
+        .map(|b| b.int("INSTRUMENT").expect("instrument") as i32)
+        .collect::<Vec<_>>();
+
     if let Some(ref sounds) = state.level_sounds(current_level) {
         // start metronome on first loop
         if beat_pos < 1.0 && !ray::is_music_playing(sounds.metronome) {
@@ -307,19 +529,7 @@ fn update_draw_frame() {
 
         // restart music
         if is_new_beat {
-            for note in state
-                .ceptre_context
-                .find_phrases4(
-                    Some("note"),
-                    None,
-                    Some(&beat_pos.floor().to_string()),
-                    Some("first"),
-                )
-                .iter()
-            {
-                let instrument = i32::from_str(note[1].as_str(&state.ceptre_context.string_cache))
-                    .expect("instrument");
-
+            for &instrument in first_instruments.iter() {
                 let sound = sounds
                     .instruments
                     .iter()
@@ -362,9 +572,11 @@ fn update_draw_frame() {
         }
     }
 
-    state
-        .ceptre_context
-        .append_state(&format!("#set-beat {}", beat_pos.floor() as i32));
+    state.append_phrase(
+        PhraseBuilder::new()
+            .atom("#set-beat")
+            .int(beat_pos.floor() as i64),
+    );
 
     if is_level_complete {
         if !is_game_complete && ray::is_key_released(ray::KEY_SPACE) {
@@ -372,9 +584,11 @@ fn update_draw_frame() {
             return;
         }
     } else if ray::is_key_pressed(ray::KEY_SPACE) {
-        state
-            .ceptre_context
-            .append_state(&format!("#input-place {}", beat_pos.floor() as i32));
+        state.append_phrase(
+            PhraseBuilder::new()
+                .atom("#input-place")
+                .int(beat_pos.floor() as i64),
+        );
 
         let selected_instrument = state.selected_instrument().expect("selected_instrument");
 
@@ -389,29 +603,46 @@ fn update_draw_frame() {
     }
 
     if ray::is_key_pressed(ray::KEY_LEFT) {
-        state.ceptre_context.append_state("#input-change-left");
+        state
+            .ceptre_context
+            .append_state("#input-change-left")
+            .expect("append_state");
     } else if ray::is_key_pressed(ray::KEY_RIGHT) {
-        state.ceptre_context.append_state("#input-change-right");
+        state
+            .ceptre_context
+            .append_state("#input-change-right")
+            .expect("append_state");
     };
 
     // state.ceptre_context.print();
 
     {
         let collide_atom = state.ceptre_context.to_atom("^collide");
-        let mut collide_pos_atom = None;
+        let mut collide_pos = None;
+
+        let outcome = ceptre::update_with_limit(
+            &mut state.ceptre_context,
+            MAX_RULE_STEPS,
+            |p: &ceptre::Phrase| {
+                if p[0].as_atom() == Some(collide_atom) && collide_pos.is_none() {
+                    collide_pos = Some(p[1].as_int().expect("pos") as i32);
+                }
 
-        ceptre::update(&mut state.ceptre_context, |p: &ceptre::Phrase| {
-            if collide_atom == p[0].string && collide_pos_atom.is_none() {
-                collide_pos_atom = Some(p[1].string);
-            }
+                None
+            },
+        );
 
-            None
-        });
+        match outcome {
+            ceptre::UpdateOutcome::Quiescent => (),
+            ceptre::UpdateOutcome::StepLimitReached => {
+                eprintln!("main.ceptre: rules didn't finish after {} steps", MAX_RULE_STEPS);
+            }
+            ceptre::UpdateOutcome::CycleDetected { rule_ids } => {
+                eprintln!("main.ceptre: rules {:?} fire in a loop", rule_ids);
+            }
+        }
 
-        if let Some(pos) = collide_pos_atom.map(|a| {
-            let s = state.ceptre_context.string_cache.from_atom(a);
-            i32::from_str(s).expect("pos")
-        }) {
+        if let Some(pos) = collide_pos {
             state.collide_beat = Some((pos, state.time));
             ray::play_sound(state.error);
         }
@@ -431,14 +662,13 @@ fn update_draw_frame() {
     let note_width = (max_x - min_x) / 16;
     let note_height = max_y - min_y;
 
-    for note in state.ceptre_context.find_phrases(Some("note")).iter() {
-        let string_cache = &state.ceptre_context.string_cache;
-        let instrument = i32::from_str(note[1].as_str(string_cache)).expect("instrument");
-        let pos = i32::from_str(note[2].as_str(string_cache)).expect("pos");
+    let notes = state.notes();
+    let temp_notes = state.temp_notes();
 
-        let x = min_x + pos * note_width;
+    for note in notes.iter() {
+        let x = min_x + note.pos * note_width;
         let y = min_y;
-        let color = instrument_color(instrument);
+        let color = instrument_color(note.instrument);
         ray::draw_rectangle(x, y, note_width, note_height, color);
     }
 
@@ -454,15 +684,11 @@ fn update_draw_frame() {
         ray::draw_rectangle(x, max_y, note_width, 10, ray::WHITE);
     }
 
-    for note in state.ceptre_context.find_phrases(Some("note-tmp")).iter() {
-        let string_cache = &state.ceptre_context.string_cache;
-        let instrument = i32::from_str(note[1].as_str(string_cache)).expect("instrument");
-        let pos = i32::from_str(note[2].as_str(string_cache)).expect("pos");
-
-        let x = min_x + pos * note_width;
+    for note in temp_notes.iter() {
+        let x = min_x + note.pos * note_width;
         let y = min_y;
         let thickness = 4;
-        let color = instrument_color(instrument);
+        let color = instrument_color(note.instrument);
 
         ray::draw_rectangle_lines_ex(
             ray::Rectangle {
@@ -489,33 +715,17 @@ fn update_draw_frame() {
         let selected_instrument = state.selected_instrument().expect("selected_instrument");
         let instrument_color = instrument_color(i);
 
-        let frac = if state
-            .ceptre_context
-            .find_phrase3(
-                Some("note"),
-                Some(&i.to_string()),
-                Some(&beat_pos.floor().to_string()),
-            )
-            .is_some()
-            || state
-                .ceptre_context
-                .find_phrase3(
-                    Some("note-tmp"),
-                    Some(&i.to_string()),
-                    Some(&beat_pos.floor().to_string()),
-                )
-                .is_some()
+        let beat = beat_pos.floor() as i32;
+
+        let frac = if notes.iter().any(|n| n.instrument == i && n.pos == beat)
+            || temp_notes.iter().any(|n| n.instrument == i && n.pos == beat)
         {
             frac
         } else {
             0.0
         };
 
-        if state
-            .ceptre_context
-            .find_phrase2(Some("note"), Some(&i.to_string()))
-            .is_some()
-        {
+        if notes.iter().any(|n| n.instrument == i) {
             let r = radius + radius2 * frac;
 
             if i == selected_instrument {
@@ -592,9 +802,33 @@ fn update_draw_frame() {
         );
     }
 
+    if let Some(ref error) = state.rules_error {
+        ray::draw_text(error, 10, HEIGHT - 20, 10, ray::RED);
+    }
+
     ray::end_drawing();
 }
 
+// The latest modification time of the program files on disk, or None if they
+// can't be read, as in the web build.
+fn program_modified() -> Option<SystemTime> {
+    PROGRAM_FILES
+        .iter()
+        .map(|&(name, _)| fs::metadata(Path::new(PROGRAM_DIR).join(name)).and_then(|m| m.modified()))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .and_then(|times| times.into_iter().max())
+}
+
+// Reports likely mistakes in the rules in debug builds.
+fn print_lints(context: &ceptre::Context) {
+    if cfg!(debug_assertions) {
+        for lint in context.lint(HOST_PREDICATES) {
+            eprintln!("{}", lint);
+        }
+    }
+}
+
 fn beat_pos_for_sound(sound: &ray::Music) -> f32 {
     let played = ray::get_music_time_played(*sound);
     let length = ray::get_music_time_length(*sound);